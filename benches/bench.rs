@@ -2,14 +2,14 @@
 extern crate test;
 use test::Bencher;
 
-use abow::{load_img_get_kps, Vocabulary};
+use abow::{load_img_get_kps, FlatVocabulary, OrbConfig, Vocabulary};
 
 /// Benchmark for Vocabulary::transform()
 #[bench]
 fn transf(b: &mut Bencher) {
 
     let voc = Vocabulary::load("vocabs/test.voc").unwrap();
-    let features = load_img_get_kps("data/test/0.jpg").unwrap();
+    let features = load_img_get_kps("data/test/0.jpg", &OrbConfig::default()).unwrap();
     b.iter(|| {
         voc.transform(&features).unwrap();
     });
@@ -20,8 +20,19 @@ fn transf(b: &mut Bencher) {
 fn transf_dir_idx(b: &mut Bencher) {
 
     let voc = Vocabulary::load("vocabs/test.voc").unwrap();
-    let features = load_img_get_kps("data/test/0.jpg").unwrap();
+    let features = load_img_get_kps("data/test/0.jpg", &OrbConfig::default()).unwrap();
     b.iter(|| {
         voc.transform_with_direct_idx(&features).unwrap();
     });
 }
+
+/// Benchmark for FlatVocabulary::transform()
+#[bench]
+fn transf_flat(b: &mut Bencher) {
+    let voc = Vocabulary::load("vocabs/test.voc").unwrap();
+    let flat = FlatVocabulary::new(&voc);
+    let features = load_img_get_kps("data/test/0.jpg", &OrbConfig::default()).unwrap();
+    b.iter(|| {
+        flat.transform(&features).unwrap();
+    });
+}
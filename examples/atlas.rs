@@ -0,0 +1,21 @@
+use abow::*;
+
+/// Write a mosaic of the patches assigned to each word of the test vocabulary, and to each
+/// node of its first level, to `atlas/`. Coherent mosaics indicate meaningful words.
+fn main() {
+    let voc = Vocabulary::load("vocabs/test.voc").unwrap();
+    let images = image_paths("data/test", &DirOptions::default()).unwrap();
+
+    for (dir, level) in [("atlas/words", None), ("atlas/level_1", Some(1))] {
+        let options = AtlasOptions {
+            level,
+            ..Default::default()
+        };
+        let mut atlas = PatchAtlas::new(&voc, options).unwrap();
+        for image in images.iter().step_by(4) {
+            atlas.add_image(image, &OrbConfig::default()).unwrap();
+        }
+        let written = atlas.write(dir).unwrap();
+        println!("Wrote {} mosaics to {}", written.len(), dir);
+    }
+}
@@ -0,0 +1,83 @@
+use std::path::Path;
+
+use abow::*;
+
+/// Compare burst suppression settings on the test images. Consecutive images in `data/test`
+/// are neighbours, so a lower cost means the top matches are closer in the sequence.
+fn main() {
+    let voc = Vocabulary::load("vocabs/test.voc").unwrap();
+
+    // Load test images, sorted by their sequence number
+    let mut images: Vec<(usize, Vec<Desc>)> = Vec::new();
+    for entry in Path::new("data/test").read_dir().expect("Error").flatten() {
+        let num = entry.path().file_stem().unwrap().to_str().unwrap().parse();
+        if let Ok(num) = num {
+            images.push((
+                num,
+                load_img_get_kps(entry.path(), &OrbConfig::default()).unwrap(),
+            ));
+        }
+    }
+    images.sort_by_key(|i| i.0);
+
+    let plain: Vec<BoW> = images
+        .iter()
+        .map(|(_, f)| voc.transform(f).unwrap())
+        .collect();
+    let settings = vec![
+        ("none", Burstiness::default()),
+        ("sqrt", Burstiness::sqrt()),
+        (
+            "power 0.3",
+            Burstiness {
+                power: Some(0.3),
+                ..Default::default()
+            },
+        ),
+        (
+            "cap 3",
+            Burstiness {
+                cap: Some(3.),
+                ..Default::default()
+            },
+        ),
+        (
+            "idf",
+            Burstiness {
+                idf: Some(Burstiness::idf_from(&plain)),
+                ..Default::default()
+            },
+        ),
+        (
+            "cap 3 + idf + sqrt",
+            Burstiness {
+                cap: Some(3.),
+                idf: Some(Burstiness::idf_from(&plain)),
+                power: Some(0.5),
+            },
+        ),
+    ];
+
+    println!("Setting            | Cost");
+    for (name, burstiness) in settings {
+        let bows: Vec<(usize, BoW)> = images
+            .iter()
+            .map(|(n, f)| (*n, voc.transform_with_burstiness(f, &burstiness).unwrap()))
+            .collect();
+
+        // Sum of sequence distances of the top 12 matches of each image
+        let mut cost = 0;
+        for (n1, bow1) in bows.iter() {
+            let mut scores: Vec<(f32, usize)> = bows
+                .iter()
+                .map(|(n2, bow2)| (bow1.l1(bow2).unwrap(), *n2))
+                .collect();
+            scores.sort_by(|a, b| b.0.partial_cmp(&a.0).unwrap());
+            cost += scores[..12]
+                .iter()
+                .map(|s| (s.1 as i64 - *n1 as i64).abs())
+                .sum::<i64>();
+        }
+        println!("{:18} | {}", name, cost);
+    }
+}
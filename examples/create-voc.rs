@@ -1,13 +1,12 @@
-use abow::{all_kps_from_dir, vocab::Vocabulary};
+use abow::{all_kps_from_dir, vocab::Vocabulary, OrbConfig};
 
 fn main() {
-
     // Extract orb descriptors from images
-    let features = all_kps_from_dir("data/train").unwrap();
+    let features = all_kps_from_dir("data/train", &OrbConfig::default()).unwrap();
     println!("Detected {} ORB features.", features.len());
 
     // Create vocabulary from features
-    let voc = Vocabulary::create(&features, 9, 3);
+    let voc = Vocabulary::create(&features, 9, 3).unwrap();
     println!("\nVocabulary = {:#?}", voc);
 
     // Save vocab and load it again just for fun
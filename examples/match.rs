@@ -13,7 +13,7 @@ fn main() {
     // Create BoW vectors from the test data. Save file name for demonstration.
     let mut bows: Vec<(PathBuf, BoW)> = Vec::new();
     for entry in Path::new("data/test").read_dir().expect("Error").flatten() {
-        let new_feat = load_img_get_kps(&entry.path()).unwrap();
+        let new_feat = load_img_get_kps(&entry.path(), &OrbConfig::default()).unwrap();
         bows.push((
             entry.path(),
             voc.transform_with_direct_idx(&new_feat).unwrap().0,
@@ -24,7 +24,7 @@ fn main() {
     for (f1, bow1) in bows.iter().take(5) {
         let mut scores: Vec<(f32, &OsStr)> = Vec::new();
         for (f2, bow2) in bows.iter() {
-            let d = bow1.l1(bow2);
+            let d = bow1.l1(bow2).unwrap();
             scores.push((d, f2.file_name().unwrap()));
         }
 
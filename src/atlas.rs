@@ -0,0 +1,230 @@
+#![cfg(feature = "opencv")]
+//! Mosaics of the image patches assigned to each visual word, for judging by eye whether the
+//! clustering groups visually similar features. A good word shows the same kind of corner or
+//! texture in every patch; a word mixing unrelated patches suggests `k` or `l` is too small.
+use crate::{
+    opencv_utils::{load_grayscale, orb_from_cvimage},
+    BowErr, BowResult, IdPath, OrbConfig, Vocabulary,
+};
+use opencv::{
+    self,
+    core::{Rect, Scalar, Size, Vector},
+    prelude::MatTraitConst,
+};
+use std::{
+    collections::BTreeMap,
+    path::{Path, PathBuf},
+};
+
+type CvMat = opencv::core::Mat;
+
+#[derive(Debug, Clone, PartialEq)]
+/// How patches are grouped and laid out.
+pub struct AtlasOptions {
+    /// Side of each patch in the mosaic, in pixels.
+    pub patch_size: i32,
+    /// Maximum number of patches kept per group. The first ones found are kept.
+    pub max_patches: usize,
+    /// Number of patches per row of a mosaic.
+    pub columns: usize,
+    /// Group patches by word if None, or by their node at this depth of the tree.
+    /// Words shallower than the depth form their own group.
+    pub level: Option<usize>,
+    /// Rotate patches so that each keypoint's orientation points right.
+    pub rotate: bool,
+}
+
+impl Default for AtlasOptions {
+    fn default() -> Self {
+        Self {
+            patch_size: 32,
+            max_patches: 64,
+            columns: 8,
+            level: None,
+            rotate: true,
+        }
+    }
+}
+
+/// Image patches grouped by visual word or by tree node.
+pub struct PatchAtlas<'a> {
+    voc: &'a Vocabulary,
+    options: AtlasOptions,
+    /// Patches by word id, or by stable node id if grouped by level.
+    groups: BTreeMap<usize, Vec<CvMat>>,
+}
+
+impl<'a> PatchAtlas<'a> {
+    /// Create an empty atlas for a vocabulary. Returns Err if the options are invalid.
+    pub fn new(voc: &'a Vocabulary, options: AtlasOptions) -> BowResult<Self> {
+        if options.patch_size < 1 || options.columns < 1 || options.level == Some(0) {
+            return Err(BowErr::InvalidParams(
+                "atlas needs patch_size >= 1, columns >= 1 and a level >= 1",
+            ));
+        }
+        Ok(Self {
+            voc,
+            options,
+            groups: BTreeMap::new(),
+        })
+    }
+
+    /// Extract orb features from an image, and add the patch around each keypoint
+    /// to the group of its word or node.
+    pub fn add_image<P: AsRef<Path>>(&mut self, path: P, config: &OrbConfig) -> BowResult<()> {
+        self.add_cvimage(&load_grayscale(path.as_ref())?, config)
+    }
+
+    /// Like [`Self::add_image`], for a grayscale image already in memory.
+    pub fn add_cvimage(&mut self, img: &CvMat, config: &OrbConfig) -> BowResult<()> {
+        let (keypoints, descriptors) = orb_from_cvimage(img, config)?;
+        if descriptors.is_empty() {
+            return Ok(());
+        }
+        let (_, direct_idx) = self.voc.transform_with_direct_idx(&descriptors)?;
+        for (kp, path) in keypoints.iter().zip(direct_idx) {
+            let group = self.group_of(&path);
+            let patches = self.groups.entry(group).or_default();
+            if patches.len() < self.options.max_patches {
+                patches.push(self.patch(img, kp)?);
+            }
+        }
+        Ok(())
+    }
+
+    /// Ids of the groups with at least one patch: word ids, or node ids if grouped by level.
+    pub fn groups(&self) -> impl Iterator<Item = usize> + '_ {
+        self.groups.keys().copied()
+    }
+
+    /// Mosaic of the patches of a group, row by row. Returns Err if the group has no patches.
+    pub fn mosaic(&self, group: usize) -> BowResult<CvMat> {
+        let patches = self
+            .groups
+            .get(&group)
+            .ok_or(BowErr::InvalidParams("atlas group has no patches"))?;
+        let p = self.options.patch_size;
+        let columns = self.options.columns.min(patches.len());
+        let rows = (patches.len() + columns - 1) / columns;
+        let mosaic = CvMat::new_rows_cols_with_default(
+            rows as i32 * p,
+            columns as i32 * p,
+            opencv::core::CV_8UC1,
+            Scalar::all(0.),
+        )?;
+        for (i, patch) in patches.iter().enumerate() {
+            let (x, y) = ((i % columns) as i32 * p, (i / columns) as i32 * p);
+            // The region shares its data with the mosaic
+            let mut region = CvMat::roi(&mosaic, Rect::new(x, y, p, p))?;
+            patch.copy_to(&mut region)?;
+        }
+        Ok(mosaic)
+    }
+
+    /// Write the mosaic of every group to `dir`, as `word_<id>.png` or `node_<id>.png`.
+    /// Returns the paths written.
+    pub fn write<P: AsRef<Path>>(&self, dir: P) -> BowResult<Vec<PathBuf>> {
+        std::fs::create_dir_all(dir.as_ref())?;
+        let prefix = match self.options.level {
+            Some(_) => "node",
+            None => "word",
+        };
+        let mut written = Vec::new();
+        for group in self.groups() {
+            let path = dir.as_ref().join(format!("{}_{}.png", prefix, group));
+            let path_str = path
+                .to_str()
+                .ok_or_else(|| BowErr::NonUtf8Path(path.clone()))?;
+            if !opencv::imgcodecs::imwrite(path_str, &self.mosaic(group)?, &Vector::new())? {
+                return Err(BowErr::Io(std::io::Error::new(
+                    std::io::ErrorKind::Other,
+                    format!("opencv could not write {:?}", path),
+                )));
+            }
+            written.push(path);
+        }
+        Ok(written)
+    }
+
+    /// Group of a feature with this path through the tree: its word id, or the stable id of
+    /// its node at the atlas level.
+    fn group_of(&self, path: &IdPath) -> usize {
+        // Paths hold the block ids below the root, then the word id, so the block at
+        // depth `level` is at index `level - 1`
+        let word = *path.last().unwrap() as usize;
+        match self.options.level {
+            Some(level) if path.len() > level => self.voc.block_node(path[level - 1] as usize),
+            Some(_) => self.voc.word(word).unwrap().id(),
+            None => word,
+        }
+    }
+
+    /// Square patch around a keypoint covering its size, scaled to `patch_size`
+    /// and rotated to its orientation if requested.
+    fn patch(&self, img: &CvMat, kp: &crate::KeyPoint) -> BowResult<CvMat> {
+        let p = self.options.patch_size as f64;
+        let scale = p / (kp.size as f64).max(1.);
+        let angle = match self.options.rotate {
+            true => (kp.angle as f64).to_radians(),
+            false => 0.,
+        };
+        let (sin, cos) = (angle.sin() * scale, angle.cos() * scale);
+        let (x, y) = (kp.x as f64, kp.y as f64);
+        // Maps the keypoint to the patch center, and its orientation to the x axis
+        let transform = CvMat::from_slice_2d(&[
+            [cos, sin, p / 2. - (cos * x + sin * y)],
+            [-sin, cos, p / 2. - (-sin * x + cos * y)],
+        ])?;
+        let mut patch = CvMat::default();
+        opencv::imgproc::warp_affine(
+            img,
+            &mut patch,
+            &transform,
+            Size::new(self.options.patch_size, self.options.patch_size),
+            opencv::imgproc::INTER_LINEAR,
+            opencv::core::BORDER_REPLICATE,
+            Scalar::default(),
+        )?;
+        Ok(patch)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::Desc;
+    use rand::{thread_rng, Rng};
+
+    #[test]
+    fn grouping() {
+        let features: Vec<Desc> = (0..500).map(|_| thread_rng().gen()).collect();
+        let voc = Vocabulary::create(&features, 3, 3).unwrap();
+        let (_, direct_idx) = voc.transform_with_direct_idx(&features).unwrap();
+
+        let by_word = PatchAtlas::new(&voc, AtlasOptions::default()).unwrap();
+        for (feature, path) in features.iter().zip(direct_idx.iter()) {
+            assert_eq!(by_word.group_of(path), voc.word_of(feature).unwrap());
+        }
+        // Level 4 is below the words, which then form their own group
+        for level in 1..=4 {
+            let options = AtlasOptions {
+                level: Some(level),
+                ..Default::default()
+            };
+            let atlas = PatchAtlas::new(&voc, options).unwrap();
+            for (feature, path) in features.iter().zip(direct_idx.iter()) {
+                let mut node = voc.word(voc.word_of(feature).unwrap()).unwrap();
+                while node.depth() > level {
+                    node = node.parent().unwrap();
+                }
+                assert_eq!(atlas.group_of(path), node.id());
+            }
+        }
+
+        let root = AtlasOptions {
+            level: Some(0),
+            ..Default::default()
+        };
+        assert!(PatchAtlas::new(&voc, root).is_err());
+    }
+}
@@ -0,0 +1,696 @@
+//! Command-line tool for training, inspecting and converting vocabularies,
+//! and for transforming and querying images.
+use std::{
+    collections::HashMap,
+    error::Error,
+    path::{Component, Path, PathBuf},
+};
+
+use abow::*;
+use clap::{Parser, Subcommand, ValueEnum};
+use serde_json::json;
+
+type CliResult<T> = Result<T, Box<dyn Error>>;
+
+/// Value of the `format` field of JSON vocabulary files.
+const JSON_FORMAT: &str = "abow vocabulary";
+
+#[derive(Parser)]
+#[command(
+    name = "abow",
+    version,
+    about = "Visual bag of words for fast image matching"
+)]
+struct Cli {
+    /// Print results as JSON instead of human-readable tables
+    #[arg(long, global = true)]
+    json: bool,
+
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Train a vocabulary from a directory of images or a descriptor file
+    Train {
+        /// Directory of training images, or descriptor file
+        input: PathBuf,
+        /// Vocabulary file to write (.voc or .json)
+        #[arg(short, long)]
+        output: PathBuf,
+        /// Branching factor
+        #[arg(short, default_value_t = 10)]
+        k: usize,
+        /// Number of levels
+        #[arg(short, default_value_t = 4)]
+        l: usize,
+        /// Random seed, for reproducible vocabularies
+        #[arg(long)]
+        seed: Option<u64>,
+        /// Word weighting
+        #[arg(long, value_enum, default_value_t = Weighting::Tf)]
+        weighting: Weighting,
+        /// Initialization of each k-means step
+        #[arg(long, value_enum, default_value_t = Init::KmeansPp)]
+        init: Init,
+        /// Clusters with fewer features than this are not split further
+        #[arg(long, default_value_t = 2)]
+        min_cluster_size: usize,
+    },
+    /// Train vocabularies for every combination of parameters, evaluate retrieval on a set of
+    /// numbered validation images (e.g. 100.jpg), and save the best vocabulary
+    Sweep {
+        /// Directory of training images, or descriptor file
+        training: PathBuf,
+        /// Directory of validation images, or descriptor file. Each image is queried against
+        /// all others
+        validation: PathBuf,
+        /// Vocabulary file to write the best vocabulary to (.voc or .json)
+        #[arg(short, long)]
+        output: PathBuf,
+        /// Branching factors
+        #[arg(short, value_delimiter = ',', default_values_t = [6, 8, 10])]
+        k: Vec<usize>,
+        /// Numbers of levels
+        #[arg(short, value_delimiter = ',', default_values_t = [3, 4, 5])]
+        l: Vec<usize>,
+        /// Initializations of each k-means step
+        #[arg(long, value_enum, value_delimiter = ',', default_values_t = [Init::KmeansPp])]
+        init: Vec<Init>,
+        /// Minimum sizes of clusters which are split further
+        #[arg(long, value_delimiter = ',', default_values_t = [2])]
+        min_cluster_size: Vec<usize>,
+        /// Word weightings
+        #[arg(long, value_enum, value_delimiter = ',', default_values_t = [Weighting::Tf])]
+        weighting: Vec<Weighting>,
+        /// Scoring functions
+        #[arg(long, value_enum, value_delimiter = ',', default_values_t = [Score::L1])]
+        scoring: Vec<Score>,
+        /// Validation images match if the numbers in their file names differ by at most this
+        #[arg(long, default_value_t = 6)]
+        tolerance: i64,
+        /// Values of k for which recall@k is computed
+        #[arg(long, value_delimiter = ',', default_values_t = [1, 5, 10])]
+        recall_at: Vec<usize>,
+        /// Metric to select the best configuration by. `recall` uses the first --recall-at
+        #[arg(long, value_enum, default_value_t = Select::Map)]
+        select: Select,
+        /// Random seed, for reproducible vocabularies
+        #[arg(long)]
+        seed: Option<u64>,
+        /// CSV file to write the results of every configuration to
+        #[arg(long)]
+        csv: Option<PathBuf>,
+    },
+    /// Print statistics of a vocabulary
+    Inspect {
+        /// Vocabulary file (.voc or .json)
+        vocabulary: PathBuf,
+    },
+    /// Transform images into BoW files
+    Transform {
+        /// Vocabulary file (.voc or .json)
+        vocabulary: PathBuf,
+        /// Images, directories of images, or descriptor files
+        #[arg(required = true)]
+        inputs: Vec<PathBuf>,
+        /// Directory to write one .bow file per image to, at the image's path relative to
+        /// its input directory with .bow appended
+        #[arg(short, long)]
+        output: PathBuf,
+    },
+    /// Find the best matches of BoW files among a database of BoW files, through an inverted
+    /// index
+    Query {
+        /// Vocabulary the BoW files were transformed with (.voc or .json)
+        vocabulary: PathBuf,
+        /// Directory of .bow files to search, including subdirectories, or database file
+        /// written with --save
+        database: PathBuf,
+        /// .bow files to look up
+        #[arg(required = true)]
+        queries: Vec<PathBuf>,
+        /// Number of matches to report per query
+        #[arg(short = 'n', long, default_value_t = 5)]
+        top: usize,
+        /// Database file to write, to query the same .bow files again without reading each
+        #[arg(long)]
+        save: Option<PathBuf>,
+    },
+    /// Export the vocabulary tree for visualization, as Graphviz DOT or JSON (by file extension)
+    Export {
+        /// Vocabulary file (.voc or .json)
+        vocabulary: PathBuf,
+        /// File to write (.dot or .json)
+        output: PathBuf,
+        /// Node id of the subtree to export
+        #[arg(long, default_value_t = 0)]
+        root: usize,
+        /// Maximum depth below the exported root
+        #[arg(long)]
+        max_depth: Option<usize>,
+        /// Maximum number of nodes to export
+        #[arg(long, default_value_t = 1000)]
+        max_nodes: usize,
+        /// Include centroid descriptors
+        #[arg(long)]
+        centroids: bool,
+    },
+    /// Convert a vocabulary between formats, chosen by file extension (.voc or .json).
+    /// Vocabularies written by abow 0.4.2 and earlier are converted to the current format
+    Convert {
+        /// Vocabulary file to read
+        input: PathBuf,
+        /// Vocabulary file to write
+        output: PathBuf,
+    },
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum Weighting {
+    /// All words have weight 1
+    Tf,
+    /// Words are weighted by inverse document frequency in the training images
+    TfIdf,
+}
+
+impl From<Weighting> for abow::Weighting {
+    fn from(weighting: Weighting) -> Self {
+        match weighting {
+            Weighting::Tf => abow::Weighting::Tf,
+            Weighting::TfIdf => abow::Weighting::TfIdf,
+        }
+    }
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum Init {
+    /// Centers chosen uniformly at random
+    Random,
+    /// k-means++ seeding
+    KmeansPp,
+}
+
+impl From<Init> for ClusterInitMethod {
+    fn from(init: Init) -> Self {
+        match init {
+            Init::Random => ClusterInitMethod::Random,
+            Init::KmeansPp => ClusterInitMethod::KMeansPP,
+        }
+    }
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum Score {
+    L1,
+    L2,
+    ChiSquare,
+    Bhattacharyya,
+    DotProduct,
+}
+
+impl From<Score> for Scoring {
+    fn from(score: Score) -> Self {
+        match score {
+            Score::L1 => Scoring::L1,
+            Score::L2 => Scoring::L2,
+            Score::ChiSquare => Scoring::ChiSquare,
+            Score::Bhattacharyya => Scoring::Bhattacharyya,
+            Score::DotProduct => Scoring::DotProduct,
+        }
+    }
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum Select {
+    /// Mean average precision
+    Map,
+    /// Area under the precision-recall curve
+    PrAuc,
+    /// Recall@k
+    Recall,
+}
+
+fn main() {
+    let cli = Cli::parse();
+    if let Err(e) = run(cli) {
+        eprintln!("Error: {}", e);
+        std::process::exit(1);
+    }
+}
+
+fn run(cli: Cli) -> CliResult<()> {
+    match cli.command {
+        Command::Train {
+            input,
+            output,
+            k,
+            l,
+            seed,
+            weighting,
+            init,
+            min_cluster_size,
+        } => {
+            let images = load_images(&[input])?;
+            let features: Vec<Desc> = images
+                .iter()
+                .flat_map(|i| i.descriptors.iter().cloned())
+                .collect();
+            let params = TrainingParams {
+                k,
+                l,
+                init: init.into(),
+                min_cluster_size,
+                seed,
+            };
+            let mut voc = Vocabulary::create_with_params(&features, &params)?;
+            if let Weighting::TfIdf = weighting {
+                let images: Vec<&[Desc]> = images
+                    .iter()
+                    .map(|i| &i.descriptors[..])
+                    .filter(|f| !f.is_empty())
+                    .collect();
+                voc.set_idf_weights(&images)?;
+            }
+            save_vocabulary(&voc, &output)?;
+            if cli.json {
+                println!("{}", vocabulary_json(&voc));
+            } else {
+                println!(
+                    "Trained on {} features from {} images.",
+                    features.len(),
+                    images.len()
+                );
+                println!("Vocabulary = {:#?}", voc);
+            }
+        }
+        Command::Sweep {
+            training,
+            validation,
+            output,
+            k,
+            l,
+            init,
+            min_cluster_size,
+            weighting,
+            scoring,
+            tolerance,
+            recall_at,
+            select,
+            seed,
+            csv,
+        } => {
+            let training: Vec<Vec<Desc>> = load_images(&[training])?
+                .into_iter()
+                .map(|i| i.descriptors)
+                .collect();
+            let mut frames = Vec::new();
+            let mut images = Vec::new();
+            for Image {
+                path, descriptors, ..
+            } in load_images(&[validation])?
+            {
+                let frame = path
+                    .file_stem()
+                    .and_then(|s| s.to_str())
+                    .and_then(|s| s.parse::<i64>().ok())
+                    .ok_or_else(|| format!("{:?} is not a numbered validation image", path))?;
+                if descriptors.is_empty() {
+                    eprintln!("Skipping {:?}: no features detected", path);
+                    continue;
+                }
+                frames.push(frame);
+                images.push(descriptors);
+            }
+            let truth = GroundTruth::Sequence {
+                queries: frames.clone(),
+                database: frames,
+                tolerance,
+                ignore_within: Some(0),
+            };
+            let criterion = match select {
+                Select::Map => Criterion::MeanAveragePrecision,
+                Select::PrAuc => Criterion::PrAuc,
+                Select::Recall => Criterion::RecallAt(*recall_at.first().unwrap_or(&1)),
+            };
+            let grid = SweepGrid {
+                k,
+                l,
+                init: init.into_iter().map(Into::into).collect(),
+                min_cluster_size,
+                weighting: weighting.into_iter().map(Into::into).collect(),
+                scoring: scoring.into_iter().map(Into::into).collect(),
+                seed,
+                recall_at,
+                criterion,
+            };
+            let result = sweep(&training, &images, &images, &truth, &grid)?;
+            save_vocabulary(&result.vocabulary, &output)?;
+            if let Some(csv) = csv {
+                result.write_csv(std::io::BufWriter::new(std::fs::File::create(csv)?))?;
+            }
+
+            if cli.json {
+                let results: Vec<_> = result
+                    .results
+                    .iter()
+                    .map(|r| {
+                        let mut r = r.clone();
+                        r.evaluation.pr_curve.clear();
+                        r
+                    })
+                    .collect();
+                println!(
+                    "{}",
+                    serde_json::to_string_pretty(&json!({
+                        "results": results,
+                        "best": result.best,
+                        "vocabulary": output,
+                    }))?
+                );
+            } else {
+                println!(
+                    "{:>3} {:>2} {:<8} {:>4} {:<6} {:<13} {:>8} {:>7} {:>10} {:>6} {:>6}",
+                    "k",
+                    "l",
+                    "init",
+                    "min",
+                    "weight",
+                    "scoring",
+                    "train s",
+                    "words",
+                    "transf ms",
+                    "mAP",
+                    "AUC"
+                );
+                for r in result.results.iter() {
+                    let c = &r.config;
+                    println!(
+                        "{:>3} {:>2} {:<8} {:>4} {:<6} {:<13} {:>8.2} {:>7} {:>10.3} {:>6.3} {:>6.3}",
+                        c.training.k,
+                        c.training.l,
+                        format!("{:?}", c.training.init),
+                        c.training.min_cluster_size,
+                        format!("{:?}", c.weighting),
+                        format!("{:?}", c.scoring),
+                        r.train_secs,
+                        r.num_words,
+                        r.transform_secs * 1000.,
+                        r.evaluation.mean_average_precision,
+                        r.evaluation.pr_auc
+                    );
+                }
+                let best = result.best();
+                println!(
+                    "\nBest: {:?}\nRecall@k: {:?}\nSaved to {}",
+                    best.config,
+                    best.evaluation.recall_at_k,
+                    output.display()
+                );
+            }
+        }
+        Command::Inspect { vocabulary } => {
+            let voc = load_vocabulary(&vocabulary)?;
+            if cli.json {
+                println!("{}", vocabulary_json(&voc));
+            } else {
+                println!("Vocabulary = {:#?}", voc);
+                println!("Nodes per level: {:?}", voc.num_nodes_per_level());
+            }
+        }
+        Command::Transform {
+            vocabulary,
+            inputs,
+            output,
+        } => {
+            let voc = load_vocabulary(&vocabulary)?;
+            let images = load_images(&inputs)?;
+            // Each image is written to its name with .bow appended, so that images which only
+            // differ by extension or directory are kept apart
+            let mut files = Vec::with_capacity(images.len());
+            let mut sources = HashMap::new();
+            for image in images.iter() {
+                let mut file = output.join(&image.name).into_os_string();
+                file.push(".bow");
+                let file = PathBuf::from(file);
+                if let Some(other) = sources.insert(file.clone(), &image.path) {
+                    return Err(format!(
+                        "{:?} and {:?} would both be written to the same .bow file",
+                        other, image.path
+                    )
+                    .into());
+                }
+                files.push(file);
+            }
+            let mut written = Vec::new();
+            for (image, file) in images.iter().zip(files) {
+                match voc.transform(&image.descriptors) {
+                    Ok(bow) => {
+                        std::fs::create_dir_all(file.parent().unwrap_or(&output))?;
+                        bow.save(&file)?
+                    }
+                    Err(BowErr::NoFeatures) => {
+                        eprintln!("Skipping {:?}: no features detected", image.path);
+                        continue;
+                    }
+                    Err(e) => return Err(e.into()),
+                }
+                written.push((image.path.clone(), file));
+            }
+            if cli.json {
+                let files: Vec<_> = written
+                    .iter()
+                    .map(|(image, bow)| json!({ "image": image, "bow": bow }))
+                    .collect();
+                println!("{}", serde_json::to_string_pretty(&files)?);
+            } else {
+                for (image, bow) in written.iter() {
+                    println!("{} -> {}", image.display(), bow.display());
+                }
+            }
+        }
+        Command::Query {
+            vocabulary,
+            database,
+            queries,
+            top,
+            save,
+        } => {
+            let voc = load_vocabulary(&vocabulary)?;
+            // Entries are identified by the path of their .bow file
+            let mut db = if database.is_dir() {
+                let mut db = Database::<PathBuf>::new(&voc);
+                let bow_files = DirOptions {
+                    recursive: true,
+                    extensions: vec!["bow".to_owned()],
+                    ..Default::default()
+                };
+                for path in image_paths(&database, &bow_files)? {
+                    let bow = BoW::load(&path)?;
+                    db.add_bow_with_metadata(&bow, path.clone()).map_err(|e| {
+                        format!("{:?} cannot be added to the database: {}", path, e)
+                    })?;
+                }
+                db
+            } else {
+                Database::load(&database, &voc)?
+            };
+            if let Some(save) = save {
+                db.save(save)?;
+            }
+
+            let mut results = Vec::new();
+            for query in queries {
+                let bow = BoW::load(&query)?;
+                let matches = db.query(&bow, top).map_err(|e| {
+                    format!("{:?} cannot be compared to {:?}: {}", query, database, e)
+                })?;
+                results.push((query, matches));
+            }
+
+            if cli.json {
+                let results: Vec<_> = results
+                    .iter()
+                    .map(|(query, matches)| {
+                        let matches: Vec<_> = matches
+                            .iter()
+                            .map(|m| json!({ "entry": m.metadata, "score": m.score }))
+                            .collect();
+                        json!({ "query": query, "matches": matches })
+                    })
+                    .collect();
+                println!("{}", serde_json::to_string_pretty(&results)?);
+            } else {
+                for (query, matches) in results.iter() {
+                    println!("\nTop {} Matches for {:?}:", top, query);
+                    println!("{:<40} | Score", "Match");
+                    for m in matches.iter() {
+                        println!("{:<40} | {}", m.metadata.display().to_string(), m.score);
+                    }
+                }
+            }
+        }
+        Command::Export {
+            vocabulary,
+            output,
+            root,
+            max_depth,
+            max_nodes,
+            centroids,
+        } => {
+            let voc = load_vocabulary(&vocabulary)?;
+            let options = ExportOptions {
+                root,
+                max_depth,
+                max_nodes: Some(max_nodes),
+                centroids,
+            };
+            let file = std::io::BufWriter::new(std::fs::File::create(&output)?);
+            if is_json(&output) {
+                voc.export_json(file, &options)?;
+            } else {
+                voc.export_dot(file, &options)?;
+            }
+            if !cli.json {
+                println!("{} -> {}", vocabulary.display(), output.display());
+            }
+        }
+        Command::Convert { input, output } => {
+            let voc = load_vocabulary(&input)?;
+            save_vocabulary(&voc, &output)?;
+            if !cli.json {
+                println!("{} -> {}", input.display(), output.display());
+            }
+        }
+    }
+    Ok(())
+}
+
+fn is_json(path: &Path) -> bool {
+    path.extension().is_some_and(|e| e == "json")
+}
+
+/// Load a vocabulary as JSON or bincode, depending on the file extension. JSON files hold
+/// the format version next to the vocabulary, like the header of bincode files.
+fn load_vocabulary(path: &Path) -> CliResult<Vocabulary> {
+    if !is_json(path) {
+        return Ok(Vocabulary::load(path)?);
+    }
+    let mut file: serde_json::Value =
+        serde_json::from_reader(std::io::BufReader::new(std::fs::File::open(path)?))?;
+    if file["format"] != JSON_FORMAT {
+        return Err(format!("{:?} is not an abow vocabulary", path).into());
+    }
+    let version = file["version"].as_u64().unwrap_or(0);
+    if version != VOCABULARY_VERSION as u64 {
+        return Err(BowErr::UnsupportedVersion {
+            kind: "Vocabulary",
+            version: version.min(u32::MAX as u64) as u32,
+        }
+        .into());
+    }
+    Ok(serde_json::from_value(file["vocabulary"].take())?)
+}
+
+/// Save a vocabulary as JSON or bincode, depending on the file extension.
+fn save_vocabulary(voc: &Vocabulary, path: &Path) -> CliResult<()> {
+    if is_json(path) {
+        let file = json!({
+            "format": JSON_FORMAT,
+            "version": VOCABULARY_VERSION,
+            "vocabulary": serde_json::to_value(voc)?,
+        });
+        serde_json::to_writer(std::io::BufWriter::new(std::fs::File::create(path)?), &file)?;
+        Ok(())
+    } else {
+        Ok(voc.save(path)?)
+    }
+}
+
+fn vocabulary_json(voc: &Vocabulary) -> serde_json::Value {
+    json!({
+        "words": voc.num_words(),
+        "blocks": voc.num_blocks(),
+        "levels": voc.levels(),
+        "branching_factor": voc.branching_factor(),
+        "nodes_per_level": voc.num_nodes_per_level(),
+        "fingerprint": format!("{:016x}", voc.fingerprint()),
+        "stats": voc.stats(),
+    })
+}
+
+/// Images and descriptor files in a directory, in sorted order. Other files are skipped.
+fn image_files(dir: &Path) -> CliResult<Vec<PathBuf>> {
+    let mut files = image_paths(dir, &DirOptions::default())?;
+    for entry in dir.read_dir()? {
+        let path = entry?.path();
+        if path.is_file() && descriptor_file::is_descriptor_file(&path) {
+            files.push(path);
+        }
+    }
+    files.sort();
+    Ok(files)
+}
+
+/// Descriptors of an image.
+struct Image {
+    /// Path of the image, or its name in a descriptor file.
+    path: PathBuf,
+    /// Relative path of the image in the directory it was found in, its file name if it was
+    /// given directly, or its name in a descriptor file.
+    name: PathBuf,
+    descriptors: Vec<Desc>,
+}
+
+/// Descriptors of each image. Descriptor files are read as they are, other files are
+/// treated as images and directories are expanded into the images and descriptor files
+/// they contain.
+fn load_images(inputs: &[PathBuf]) -> CliResult<Vec<Image>> {
+    let mut images = Vec::new();
+    for input in inputs {
+        let (dir, paths) = if input.is_dir() {
+            (input.as_path(), image_files(input)?)
+        } else {
+            (input.parent().unwrap_or(input), vec![input.clone()])
+        };
+        for path in paths {
+            if descriptor_file::is_descriptor_file(&path) {
+                let file = descriptor_file::read(&path)?;
+                images.extend(file.into_iter().map(|i| Image {
+                    path: i.name.clone().into(),
+                    name: relative_name(Path::new(&i.name)),
+                    descriptors: i.descriptors,
+                }));
+            } else {
+                images.push(Image {
+                    name: relative_name(path.strip_prefix(dir).unwrap_or(&path)),
+                    descriptors: extract_descriptors(&path)?,
+                    path,
+                });
+            }
+        }
+    }
+    Ok(images)
+}
+
+/// The normal components of a path, so that it stays inside the directory it is joined to.
+fn relative_name(path: &Path) -> PathBuf {
+    path.components()
+        .filter(|c| matches!(c, Component::Normal(_)))
+        .collect()
+}
+
+#[cfg(any(feature = "opencv", feature = "native-features"))]
+fn extract_descriptors(path: &Path) -> CliResult<Vec<Desc>> {
+    Ok(load_img_get_kps(path, &OrbConfig::default())?)
+}
+
+#[cfg(not(any(feature = "opencv", feature = "native-features")))]
+fn extract_descriptors(path: &Path) -> CliResult<Vec<Desc>> {
+    Err(format!(
+        "{:?} is not a descriptor file, and reading images requires the `opencv` or `native-features` feature",
+        path
+    )
+    .into())
+}
@@ -0,0 +1,197 @@
+use crate::*;
+
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone, Default)]
+/// Suppression of bursty words, which fire many times in a single image
+/// (repetitive texture such as brick walls, tiles and foliage) and swamp the score.
+///
+/// Every step is optional, and the default does nothing. The steps are applied to the
+/// accumulated word weights of an image, in order, before the BoW is l1 normalized:
+/// 1. `cap`: intra-image capping of each word's weight.
+/// 2. `idf`: inter-image penalty of words which occur in many images.
+/// 3. `power`: power-law normalization of each word's weight.
+///
+/// Can be set per vocabulary with [`Vocabulary::set_burstiness`], or per call with
+/// [`Vocabulary::transform_with_burstiness`].
+pub struct Burstiness {
+    /// Maximum weight of a word in one image. With unit word weights, this is the
+    /// maximum number of occurrences counted per word. (Must be > 0)
+    pub cap: Option<f32>,
+    /// Inverse document frequency of each word, see [`Burstiness::idf_from`].
+    /// Only applied to word-level BoW vectors. (Must have one finite weight >= 0 per word)
+    pub idf: Option<Vec<f32>>,
+    /// Exponent `alpha` of the power-law normalization `w -> w^alpha`.
+    /// `0.5` is square-root normalization. (Must be in (0, 1])
+    pub power: Option<f32>,
+}
+
+impl Burstiness {
+    /// Square-root normalization, the most common choice.
+    pub fn sqrt() -> Self {
+        Self {
+            power: Some(0.5),
+            ..Default::default()
+        }
+    }
+
+    /// Inverse document frequency `ln(N / n_w)` of each word, where `n_w` of the `N` BoW vectors
+    /// contain word `w`. Words which never occur get the largest penalty-free weight `ln(N)`.
+    pub fn idf_from(bows: &[BoW]) -> Vec<f32> {
+        let len = bows.iter().map(|b| b.0.len()).max().unwrap_or(0);
+        let mut doc_freq = vec![0_usize; len];
+        for bow in bows {
+            for (n, &w) in doc_freq.iter_mut().zip(&bow.0) {
+                if w > 0. {
+                    *n += 1;
+                }
+            }
+        }
+        idf_from_counts(&doc_freq, bows.len())
+    }
+
+    /// Returns Err if any of the parameters is out of range for a vocabulary of `num_words`
+    /// words.
+    pub fn validate(&self, num_words: usize) -> BowResult<()> {
+        if matches!(self.cap, Some(c) if c.is_nan() || c <= 0.) {
+            return Err(BowErr::InvalidParams("burstiness cap must be > 0"));
+        }
+        if let Some(idf) = self.idf.as_ref() {
+            if idf.len() != num_words {
+                return Err(BowErr::DimensionMismatch {
+                    expected: num_words,
+                    found: idf.len(),
+                });
+            }
+            if !idf.iter().all(|w| w.is_finite() && *w >= 0.) {
+                return Err(BowErr::InvalidParams(
+                    "burstiness idf weights must be finite and >= 0",
+                ));
+            }
+        }
+        if matches!(self.power, Some(p) if p.is_nan() || p <= 0. || p > 1.) {
+            return Err(BowErr::InvalidParams("burstiness power must be in (0, 1]"));
+        }
+        Ok(())
+    }
+
+    /// Apply burst suppression to accumulated (not yet normalized) word weights.
+    /// Returns Err if the idf weights are not one per word.
+    pub(crate) fn apply(&self, bow: &mut BoW) -> BowResult<()> {
+        if let Some(idf) = self.idf.as_ref() {
+            if idf.len() != bow.0.len() {
+                return Err(BowErr::DimensionMismatch {
+                    expected: bow.0.len(),
+                    found: idf.len(),
+                });
+            }
+        }
+        self.apply_steps(bow, self.idf.as_deref());
+        Ok(())
+    }
+
+    /// Apply the steps which do not depend on the word, to accumulated node weights.
+    pub(crate) fn apply_to_nodes(&self, bow: &mut BoW) {
+        self.apply_steps(bow, None)
+    }
+
+    fn apply_steps(&self, bow: &mut BoW, idf: Option<&[f32]>) {
+        if let Some(cap) = self.cap {
+            for w in bow.0.iter_mut() {
+                *w = w.min(cap);
+            }
+        }
+        if let Some(idf) = idf {
+            for (w, idf) in bow.0.iter_mut().zip(idf) {
+                *w *= idf;
+            }
+        }
+        if let Some(power) = self.power {
+            for w in bow.0.iter_mut() {
+                *w = w.powf(power);
+            }
+        }
+    }
+}
+
+/// Inverse document frequency of each word, from the number of documents containing it.
+pub(crate) fn idf_from_counts(doc_freq: &[usize], num_docs: usize) -> Vec<f32> {
+    doc_freq
+        .iter()
+        .map(|&n| (num_docs as f32 / n.max(1) as f32).ln())
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use rand::{thread_rng, Rng};
+
+    #[test]
+    fn burst_suppression() {
+        let features: Vec<Desc> = (0..1000).map(|_| thread_rng().gen()).collect();
+        let mut voc = Vocabulary::create(&features, 6, 3).unwrap();
+
+        // One descriptor repeated many times, as on a repetitive texture
+        let mut query = features[..50].to_vec();
+        query.resize(100, features[0]);
+        let bursty_word = |bow: &BoW| bow.0.iter().cloned().fold(0., f32::max);
+
+        let plain = voc.transform(&query).unwrap();
+        let capped = Burstiness {
+            cap: Some(1.),
+            ..Default::default()
+        };
+        let capped_bow = voc.transform_with_burstiness(&query, &capped).unwrap();
+        let sqrt_bow = voc
+            .transform_with_burstiness(&query, &Burstiness::sqrt())
+            .unwrap();
+        assert!(bursty_word(&capped_bow) < bursty_word(&sqrt_bow));
+        assert!(bursty_word(&sqrt_bow) < bursty_word(&plain));
+
+        // Per-vocabulary setting is used by all transforms
+        voc.set_burstiness(Burstiness::sqrt()).unwrap();
+        assert_eq!(voc.transform(&query).unwrap(), sqrt_bow);
+        assert_eq!(
+            FlatVocabulary::new(&voc).transform(&query).unwrap(),
+            sqrt_bow
+        );
+
+        let invalid = Burstiness {
+            power: Some(2.),
+            ..Default::default()
+        };
+        assert!(voc.set_burstiness(invalid).is_err());
+
+        // idf weights must match the vocabulary
+        let bows = [voc.transform(&features[..100]).unwrap()];
+        let mut idf = Burstiness {
+            idf: Some(Burstiness::idf_from(&bows)),
+            ..Default::default()
+        };
+        assert!(voc.set_burstiness(idf.clone()).is_ok());
+        let words = idf.idf.as_mut().unwrap();
+        words[0] = f32::NAN;
+        assert!(voc.set_burstiness(idf.clone()).is_err());
+        let words = idf.idf.as_mut().unwrap();
+        words[0] = -1.;
+        assert!(voc.transform_with_burstiness(&query, &idf).is_err());
+        idf.idf.as_mut().unwrap().pop();
+        assert!(matches!(
+            voc.set_burstiness(idf.clone()),
+            Err(BowErr::DimensionMismatch { .. })
+        ));
+
+        // Loaded vocabularies are not validated, so transforms check the length too
+        voc.burstiness = idf;
+        assert!(voc.transform(&query).is_err());
+        assert!(FlatVocabulary::new(&voc).transform(&query).is_err());
+        assert!(voc.transform_at_level(&query, 1).is_ok());
+    }
+
+    #[test]
+    fn idf() {
+        let bows = vec![BoW(vec![1., 0., 0.], None), BoW(vec![1., 1., 0.], None)];
+        let idf = Burstiness::idf_from(&bows);
+        assert_eq!(idf[0], 0.);
+        assert!(idf[1] > 0. && idf[1] <= idf[2]);
+    }
+}
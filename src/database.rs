@@ -0,0 +1,1278 @@
+//! Database of BoW vectors with an inverted index, for finding the images most similar to a
+//! query without comparing it to every entry.
+//!
+//! A database belongs to the vocabulary it was created with, identified by the vocabulary's
+//! [`fingerprint`](Vocabulary::fingerprint). Features must be transformed by that vocabulary,
+//! and a saved database can only be loaded with it.
+//!
+//! **File format** (integers little-endian), with the `bincode` feature:
+//! ```text
+//! magic        8 bytes   b"ABOWDB\0\0"
+//! version      u32       1
+//! records, each:
+//!   len        u64
+//!   data       len bytes of bincode
+//! ```
+//! The first record is the [tag](Metadata::tag) of the metadata type, and the second a
+//! snapshot of the whole database written by [`Database::save`]. Each further record is an
+//! `(entry id, entry or None if removed)` pair appended by [`Database::checkpoint`], so that
+//! long sessions can persist changes without rewriting the file. An incomplete last record,
+//! left by an interrupted checkpoint, is ignored when loading and overwritten by the next
+//! checkpoint. [`Database::compact`] rewrites the file without the space taken by removed and
+//! replaced entries.
+//!
+//! Entries carry user metadata of type `M`, e.g. a timestamp or session id, which is
+//! serialized with each entry. A file must be loaded with the metadata type it was saved with:
+//! bincode does not describe the types it encodes, so files start with the tag of their
+//! [`Metadata`] type, and loading with a type of another tag is refused.
+use crate::*;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+#[cfg(feature = "bincode")]
+use std::{
+    collections::BTreeSet,
+    convert::TryInto,
+    fs::{File, OpenOptions},
+    io::{BufReader, BufWriter, Read, Seek, SeekFrom, Write},
+    path::Path,
+};
+use std::{convert::TryFrom, fmt, ops::Range, path::PathBuf};
+
+#[cfg(feature = "bincode")]
+const MAGIC: &[u8; 8] = b"ABOWDB\0\0";
+#[cfg(feature = "bincode")]
+const VERSION: u32 = 1;
+
+/// Index of an entry, in the order entries were added. Ids of removed entries are not reused,
+/// until the database is compacted.
+pub type EntryId = usize;
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+/// An entry matching a query.
+pub struct QueryResult<M = ()> {
+    /// Id of the entry.
+    pub entry: EntryId,
+    /// Similarity to the query, as computed by [`BoW::l1`].
+    pub score: f32,
+    /// Metadata of the entry.
+    pub metadata: M,
+}
+
+/// Which entries a query may return. See [`QueryOptions`].
+pub enum QueryFilter<'a, M = ()> {
+    /// Every entry.
+    All,
+    /// Only entries with an id in one of the ranges, e.g.
+    /// `vec![0..db.next_id().saturating_sub(n)]` to skip the last `n` ids, whether or not
+    /// their entries were removed. Posting lists are sorted by id, so the other entries are
+    /// not visited.
+    Ranges(Vec<Range<EntryId>>),
+    /// Only entries for which the function of their id and metadata returns true. It is called
+    /// at most once per entry and query, for the entries sharing a word with the query.
+    Fn(&'a dyn Fn(EntryId, &M) -> bool),
+}
+
+impl<M> fmt::Debug for QueryFilter<'_, M> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            QueryFilter::All => write!(f, "All"),
+            QueryFilter::Ranges(ranges) => f.debug_tuple("Ranges").field(ranges).finish(),
+            QueryFilter::Fn(_) => write!(f, "Fn"),
+        }
+    }
+}
+
+#[derive(Debug)]
+/// Constraints of a query. See [`Database::query_with`].
+pub struct QueryOptions<'a, M = ()> {
+    /// Maximum number of results.
+    pub max_results: usize,
+    /// Results scoring less than this are left out, so that fewer results are returned
+    /// when nothing is similar enough.
+    pub min_score: f32,
+    /// Entries which may be returned. Applied while scoring, so excluded entries cost nothing.
+    pub filter: QueryFilter<'a, M>,
+}
+
+impl<M> Default for QueryOptions<'_, M> {
+    fn default() -> Self {
+        Self {
+            max_results: 10,
+            min_score: 0.,
+            filter: QueryFilter::All,
+        }
+    }
+}
+
+/// Metadata of database entries. Bincode does not record the types it encodes, so database
+/// files hold the tag of their metadata type, and are only loaded with a type of the same tag.
+///
+/// ```
+/// # use abow::Metadata;
+/// # use serde::{Deserialize, Serialize};
+/// #[derive(Serialize, Deserialize)]
+/// struct Keyframe {
+///     session: u32,
+///     timestamp: f64,
+/// }
+///
+/// impl Metadata for Keyframe {
+///     fn tag() -> String {
+///         "keyframe v1".to_owned()
+///     }
+/// }
+/// ```
+pub trait Metadata: Serialize + DeserializeOwned {
+    /// Name of the serialized layout of the type. It must change whenever the layout does,
+    /// and can stay the same when the type is renamed or moved.
+    fn tag() -> String;
+}
+
+macro_rules! metadata_tags {
+    ($($t:ty),*) => {
+        $(impl Metadata for $t {
+            fn tag() -> String {
+                stringify!($t).to_owned()
+            }
+        })*
+    };
+}
+
+metadata_tags!(
+    (),
+    bool,
+    char,
+    u8,
+    u16,
+    u32,
+    u64,
+    usize,
+    i8,
+    i16,
+    i32,
+    i64,
+    isize,
+    f32,
+    f64,
+    String,
+    PathBuf
+);
+
+macro_rules! tuple_metadata_tags {
+    ($($t:ident),*) => {
+        impl<$($t: Metadata),*> Metadata for ($($t,)*) {
+            fn tag() -> String {
+                format!("({})", [$($t::tag()),*].join(", "))
+            }
+        }
+    };
+}
+
+tuple_metadata_tags!(A, B);
+tuple_metadata_tags!(A, B, C);
+tuple_metadata_tags!(A, B, C, D);
+
+impl<T: Metadata> Metadata for Option<T> {
+    fn tag() -> String {
+        format!("Option<{}>", T::tag())
+    }
+}
+
+impl<T: Metadata> Metadata for Vec<T> {
+    fn tag() -> String {
+        format!("Vec<{}>", T::tag())
+    }
+}
+
+/// (word id, weight) of the nonzero words of a BoW vector, by word id.
+type Words = Vec<(u32, f32)>;
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+/// An image added to the database.
+struct Entry<M> {
+    words: Words,
+    /// Path through the tree of each of the image's features, if the database keeps them.
+    direct_idx: Option<DirectIdx>,
+    metadata: M,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(try_from = "Snapshot<M>")]
+/// Collection of images' BoW vectors, searchable through an inverted index from each word to
+/// the entries containing it. Optionally keeps the direct index of each entry, e.g. for
+/// matching features between a query and a candidate during geometric verification.
+///
+/// Each entry has metadata of type `M`, which is returned with query results and can be used
+/// to [filter](QueryFilter::Fn) queries. Deserializing a database fails unless its inverted
+/// index matches its entries.
+pub struct Database<M = ()> {
+    fingerprint: u64,
+    num_words: usize,
+    keep_direct_idx: bool,
+    /// Entries by id, None for removed entries.
+    entries: Vec<Option<Entry<M>>>,
+    /// Number of entries which are not removed.
+    num_entries: usize,
+    /// (entry, weight) of the entries containing each word, by entry id.
+    inverted: Vec<Vec<(u32, f32)>>,
+    /// The file the database was last saved to or loaded from.
+    #[cfg(feature = "bincode")]
+    #[serde(skip)]
+    file: Option<DatabaseFile>,
+}
+
+#[derive(Deserialize)]
+/// The serialized fields of a [`Database`], which is only built from them once they are
+/// checked to be consistent.
+struct Snapshot<M> {
+    fingerprint: u64,
+    num_words: usize,
+    keep_direct_idx: bool,
+    entries: Vec<Option<Entry<M>>>,
+    num_entries: usize,
+    inverted: Vec<Vec<(u32, f32)>>,
+}
+
+impl<M> TryFrom<Snapshot<M>> for Database<M> {
+    type Error = BowErr;
+
+    fn try_from(snapshot: Snapshot<M>) -> BowResult<Self> {
+        let db = Self {
+            fingerprint: snapshot.fingerprint,
+            num_words: snapshot.num_words,
+            keep_direct_idx: snapshot.keep_direct_idx,
+            entries: snapshot.entries,
+            num_entries: snapshot.num_entries,
+            inverted: snapshot.inverted,
+            #[cfg(feature = "bincode")]
+            file: None,
+        };
+        db.check_index()?;
+        Ok(db)
+    }
+}
+
+#[cfg(feature = "bincode")]
+#[derive(Debug, Clone)]
+struct DatabaseFile {
+    path: PathBuf,
+    /// Length of the file up to the end of the last complete record.
+    len: u64,
+    /// Ids of the entries added, removed or replaced since the file was last written.
+    changed: BTreeSet<EntryId>,
+}
+
+impl<M> Database<M> {
+    /// Create an empty database for BoW vectors of a vocabulary.
+    pub fn new(voc: &Vocabulary) -> Self {
+        Self {
+            fingerprint: voc.fingerprint(),
+            num_words: voc.num_words(),
+            keep_direct_idx: false,
+            entries: Vec::new(),
+            num_entries: 0,
+            inverted: vec![Vec::new(); voc.num_words()],
+            #[cfg(feature = "bincode")]
+            file: None,
+        }
+    }
+
+    /// Create an empty database which also keeps the direct index of each entry.
+    pub fn with_direct_index(voc: &Vocabulary) -> Self {
+        Self {
+            keep_direct_idx: true,
+            ..Self::new(voc)
+        }
+    }
+
+    /// Fingerprint of the vocabulary the database was created with.
+    pub fn fingerprint(&self) -> u64 {
+        self.fingerprint
+    }
+
+    /// Number of entries, not counting removed ones.
+    pub fn len(&self) -> usize {
+        self.num_entries
+    }
+
+    /// Returns true if the database has no entries.
+    pub fn is_empty(&self) -> bool {
+        self.num_entries == 0
+    }
+
+    /// Id of the next entry to be added, one more than the largest id, counting removed
+    /// entries until the database is compacted.
+    pub fn next_id(&self) -> EntryId {
+        self.entries.len()
+    }
+
+    /// Returns true if the database has an entry with this id, which was not removed.
+    pub fn contains(&self, entry: EntryId) -> bool {
+        matches!(self.entries.get(entry), Some(Some(_)))
+    }
+
+    /// Ids of the entries which were not removed, in increasing order.
+    pub fn ids(&self) -> impl Iterator<Item = EntryId> + '_ {
+        self.entries
+            .iter()
+            .enumerate()
+            .filter(|(_, e)| e.is_some())
+            .map(|(id, _)| id)
+    }
+
+    /// Returns true if the database keeps the direct index of each entry.
+    pub fn has_direct_index(&self) -> bool {
+        self.keep_direct_idx
+    }
+
+    /// Transform an image's features with the database's vocabulary and add them as a new entry
+    /// with default metadata. Returns Err if `voc` is not the database's vocabulary, or features
+    /// is empty.
+    pub fn add(&mut self, voc: &Vocabulary, features: &[Desc]) -> BowResult<EntryId>
+    where
+        M: Default,
+    {
+        self.add_with_metadata(voc, features, M::default())
+    }
+
+    /// Like [`Self::add`], with the entry's metadata.
+    pub fn add_with_metadata(
+        &mut self,
+        voc: &Vocabulary,
+        features: &[Desc],
+        metadata: M,
+    ) -> BowResult<EntryId> {
+        let (words, direct_idx) = self.index_features(voc, features)?;
+        Ok(self.push(words, direct_idx, metadata))
+    }
+
+    /// Add a BoW vector as a new entry with default metadata. Returns Err if it was produced by
+    /// another vocabulary or does not have one weight per word, or if the database keeps direct
+    /// indices, which need the features (see [`Self::add`]).
+    pub fn add_bow(&mut self, bow: &BoW) -> BowResult<EntryId>
+    where
+        M: Default,
+    {
+        self.add_bow_with_metadata(bow, M::default())
+    }
+
+    /// Like [`Self::add_bow`], with the entry's metadata.
+    pub fn add_bow_with_metadata(&mut self, bow: &BoW, metadata: M) -> BowResult<EntryId> {
+        let words = self.index_bow(bow)?;
+        Ok(self.push(words, None, metadata))
+    }
+
+    /// Remove an entry, so that queries no longer return it.
+    /// Returns Err if there is no such entry.
+    pub fn remove(&mut self, entry: EntryId) -> BowResult<()> {
+        self.check_entry(entry)?;
+        self.set(entry, None);
+        Ok(())
+    }
+
+    /// Replace the BoW vector of an entry, keeping its id and metadata. Returns Err if there is
+    /// no such entry, or for the same reasons as [`Self::add_bow`].
+    pub fn replace(&mut self, entry: EntryId, bow: &BoW) -> BowResult<()> {
+        self.check_entry(entry)?;
+        let words = self.index_bow(bow)?;
+        self.replace_words(entry, words, None);
+        Ok(())
+    }
+
+    /// Replace an entry with an image's features, and its direct index if the database keeps
+    /// them, keeping its metadata. Returns Err if there is no such entry, or for the same
+    /// reasons as [`Self::add`].
+    pub fn replace_features(
+        &mut self,
+        entry: EntryId,
+        voc: &Vocabulary,
+        features: &[Desc],
+    ) -> BowResult<()> {
+        self.check_entry(entry)?;
+        let (words, direct_idx) = self.index_features(voc, features)?;
+        self.replace_words(entry, words, direct_idx);
+        Ok(())
+    }
+
+    /// Metadata of an entry, or None if there is no such entry.
+    pub fn metadata(&self, entry: EntryId) -> Option<&M> {
+        Some(&self.entries.get(entry)?.as_ref()?.metadata)
+    }
+
+    /// Replace the metadata of an entry. Returns Err if there is no such entry.
+    pub fn set_metadata(&mut self, entry: EntryId, metadata: M) -> BowResult<()> {
+        self.check_entry(entry)?;
+        self.entries[entry].as_mut().unwrap().metadata = metadata;
+        self.mark_changed(entry);
+        Ok(())
+    }
+
+    /// Renumber the entries to close the gaps left by removed ones, and release unused memory.
+    /// If the database has a file, it is rewritten without the removed and replaced entries.
+    /// Returns the new id of each old id, or None for removed entries. If the file cannot be
+    /// rewritten, the database and its file are left as they were.
+    pub fn compact(&mut self) -> BowResult<Vec<Option<EntryId>>>
+    where
+        M: Metadata,
+    {
+        let old_len = self.entries.len();
+        let mut ids = Vec::with_capacity(old_len);
+        let mut old_ids = Vec::with_capacity(self.num_entries);
+        for (id, entry) in self.entries.iter().enumerate() {
+            ids.push(entry.as_ref().map(|_| old_ids.len()));
+            if entry.is_some() {
+                old_ids.push(Some(id));
+            }
+        }
+        self.renumber(&ids, old_ids.len());
+        #[cfg(feature = "bincode")]
+        if let Some(path) = self.file.as_ref().map(|f| f.path.clone()) {
+            // The file still holds the old ids, which its later checkpoints must match
+            if let Err(e) = self.save(path) {
+                self.renumber(&old_ids, old_len);
+                return Err(e);
+            }
+        }
+        for postings in self.inverted.iter_mut() {
+            postings.shrink_to_fit();
+        }
+        Ok(ids)
+    }
+
+    /// Move each entry to its new id in `ids`, in a database of `len` ids. `ids` must keep
+    /// the order of entries, so that posting lists stay sorted.
+    fn renumber(&mut self, ids: &[Option<EntryId>], len: usize) {
+        let mut entries: Vec<_> = (0..len).map(|_| None).collect();
+        for (entry, id) in std::mem::take(&mut self.entries).into_iter().zip(ids) {
+            if let Some(id) = id {
+                entries[*id] = entry;
+            }
+        }
+        self.entries = entries;
+        for posting in self.inverted.iter_mut().flatten() {
+            posting.0 = ids[posting.0 as usize].unwrap() as u32;
+        }
+    }
+
+    /// BoW vector of an entry, or None if there is no such entry.
+    pub fn bow(&self, entry: EntryId) -> Option<BoW> {
+        let entry = self.entries.get(entry)?.as_ref()?;
+        let mut bow = BoW(vec![0.; self.num_words], Some(self.fingerprint));
+        for &(word, weight) in entry.words.iter() {
+            bow.0[word as usize] = weight;
+        }
+        Some(bow)
+    }
+
+    /// Direct index of an entry, if the database keeps them.
+    pub fn direct_index(&self, entry: EntryId) -> Option<&DirectIdx> {
+        self.entries.get(entry)?.as_ref()?.direct_idx.as_ref()
+    }
+
+    /// The `n` entries most similar to a query, best first. Only entries sharing at least one
+    /// word with the query are returned. Scores are equal to [`BoW::l1`] for l1 normalized
+    /// vectors, but only the words of the query are visited.
+    /// Returns Err if the query was produced by another vocabulary or does not have one weight
+    /// per word.
+    pub fn query(&self, bow: &BoW, n: usize) -> BowResult<Vec<QueryResult<M>>>
+    where
+        M: Clone,
+    {
+        let options = QueryOptions {
+            max_results: n,
+            ..Default::default()
+        };
+        self.query_with(bow, &options)
+    }
+
+    /// Like [`Self::query`], with a filter on the entries and a minimum score.
+    pub fn query_with(&self, bow: &BoW, options: &QueryOptions<M>) -> BowResult<Vec<QueryResult<M>>>
+    where
+        M: Clone,
+    {
+        self.check_bow(bow)?;
+        let mut scores = vec![0_f32; self.entries.len()];
+        let mut seen = vec![false; self.entries.len()];
+        let mut accumulate = |q: f32, postings: &[(u32, f32)]| {
+            for &(entry, d) in postings {
+                // With l1 normalized vectors, 1 - 0.5 * |q - d|_1 is the sum of min(q_i, d_i)
+                scores[entry as usize] += q.min(d);
+                seen[entry as usize] = true;
+            }
+        };
+
+        let mut ranges = match &options.filter {
+            QueryFilter::Ranges(ranges) => ranges.clone(),
+            _ => Vec::new(),
+        };
+        ranges.sort_by_key(|r| r.start);
+        // Whether each entry passes a filter function: 0 if not called yet, 1 if so, 2 if not
+        let mut allowed = match options.filter {
+            QueryFilter::Fn(_) => vec![0_u8; self.entries.len()],
+            _ => Vec::new(),
+        };
+        let mut filtered = Vec::new();
+        for (word, &q) in bow.0.iter().enumerate().filter(|(_, &q)| q > 0.) {
+            let postings = &self.inverted[word];
+            match options.filter {
+                QueryFilter::All => accumulate(q, postings),
+                QueryFilter::Ranges(_) => {
+                    let mut from = 0;
+                    for range in ranges.iter() {
+                        let start =
+                            from.max(postings.partition_point(|p| (p.0 as usize) < range.start));
+                        let end =
+                            start.max(postings.partition_point(|p| (p.0 as usize) < range.end));
+                        accumulate(q, &postings[start..end]);
+                        // Overlapping ranges count each entry once
+                        from = end;
+                    }
+                }
+                QueryFilter::Fn(f) => {
+                    filtered.clear();
+                    for &posting in postings.iter() {
+                        let allowed = &mut allowed[posting.0 as usize];
+                        if *allowed == 0 {
+                            let id = posting.0 as usize;
+                            *allowed = if f(id, &self.live(id).metadata) { 1 } else { 2 };
+                        }
+                        if *allowed == 1 {
+                            filtered.push(posting);
+                        }
+                    }
+                    accumulate(q, &filtered);
+                }
+            }
+        }
+
+        let mut results: Vec<(EntryId, f32)> = scores
+            .into_iter()
+            .enumerate()
+            .filter(|&(entry, score)| seen[entry] && score >= options.min_score)
+            .collect();
+        results.sort_by(|a, b| b.1.total_cmp(&a.1));
+        results.truncate(options.max_results);
+        // Metadata is only cloned for the returned entries
+        Ok(results
+            .into_iter()
+            .map(|(entry, score)| QueryResult {
+                entry,
+                score,
+                metadata: self.live(entry).metadata.clone(),
+            })
+            .collect())
+    }
+
+    /// Returns Err if `voc` is not the vocabulary the database was created with.
+    pub fn check_vocabulary(&self, voc: &Vocabulary) -> BowResult<()> {
+        match voc.fingerprint() {
+            f if f == self.fingerprint => Ok(()),
+            found => Err(BowErr::VocabularyMismatch {
+                expected: self.fingerprint,
+                found,
+            }),
+        }
+    }
+
+    /// Returns Err if the entries refer to words outside the vocabulary, or the inverted index
+    /// and entry count do not match the entries. Queries and updates rely on them matching,
+    /// so deserialized databases are checked.
+    fn check_index(&self) -> BowResult<()> {
+        if self.entries.len() > u32::MAX as usize {
+            return Err(BowErr::DatabaseFormat("too many entries".to_owned()));
+        }
+        let mut inverted = vec![Vec::new(); self.num_words];
+        for (id, entry) in self.entries.iter().enumerate() {
+            if let Some(entry) = entry {
+                check_words(&entry.words, self.num_words)?;
+                for &(word, weight) in entry.words.iter() {
+                    inverted[word as usize].push((id as u32, weight));
+                }
+            }
+        }
+        let num_entries = self.entries.iter().filter(|e| e.is_some()).count();
+        match inverted == self.inverted && num_entries == self.num_entries {
+            true => Ok(()),
+            false => Err(BowErr::DatabaseFormat(
+                "inverted index does not match the entries".to_owned(),
+            )),
+        }
+    }
+
+    /// Returns Err if a BoW vector was not produced by the database's vocabulary.
+    fn check_bow(&self, bow: &BoW) -> BowResult<()> {
+        if let Some(found) = bow.1.filter(|&f| f != self.fingerprint) {
+            return Err(BowErr::VocabularyMismatch {
+                expected: self.fingerprint,
+                found,
+            });
+        }
+        match bow.0.len() == self.num_words {
+            true => Ok(()),
+            false => Err(BowErr::DimensionMismatch {
+                expected: self.num_words,
+                found: bow.0.len(),
+            }),
+        }
+    }
+
+    fn check_entry(&self, entry: EntryId) -> BowResult<()> {
+        match self.contains(entry) {
+            true => Ok(()),
+            false => Err(BowErr::UnknownEntry(entry)),
+        }
+    }
+
+    /// An entry which is known to exist.
+    fn live(&self, entry: EntryId) -> &Entry<M> {
+        self.entries[entry].as_ref().expect("entry was removed")
+    }
+
+    /// Words and direct index of an image's features.
+    fn index_features(
+        &self,
+        voc: &Vocabulary,
+        features: &[Desc],
+    ) -> BowResult<(Words, Option<DirectIdx>)> {
+        self.check_vocabulary(voc)?;
+        let (bow, direct_idx) = match self.keep_direct_idx {
+            true => {
+                let (bow, di) = voc.transform_with_direct_idx(features)?;
+                (bow, Some(di))
+            }
+            false => (voc.transform(features)?, None),
+        };
+        Ok((sparse(&bow), direct_idx))
+    }
+
+    fn index_bow(&self, bow: &BoW) -> BowResult<Words> {
+        if self.keep_direct_idx {
+            return Err(BowErr::InvalidParams(
+                "database keeps direct indices, so entries must be added from features",
+            ));
+        }
+        self.check_bow(bow)?;
+        Ok(sparse(bow))
+    }
+
+    fn push(&mut self, words: Words, direct_idx: Option<DirectIdx>, metadata: M) -> EntryId {
+        let id = self.entries.len();
+        let entry = Entry {
+            words,
+            direct_idx,
+            metadata,
+        };
+        self.set(id, Some(entry));
+        id
+    }
+
+    fn replace_words(&mut self, id: EntryId, words: Words, direct_idx: Option<DirectIdx>) {
+        let metadata = self.set(id, None).expect("entry was removed").metadata;
+        let entry = Entry {
+            words,
+            direct_idx,
+            metadata,
+        };
+        self.set(id, Some(entry));
+    }
+
+    /// Set, replace or remove (with None) the entry with an id, which is at most the number
+    /// of ids, and update the posting lists of its words. Returns the previous entry.
+    fn set(&mut self, id: EntryId, entry: Option<Entry<M>>) -> Option<Entry<M>> {
+        if id == self.entries.len() {
+            self.entries.push(None);
+        }
+        let old = self.entries[id].take();
+        if let Some(old) = old.as_ref() {
+            self.num_entries -= 1;
+            for &(word, _) in old.words.iter() {
+                let postings = &mut self.inverted[word as usize];
+                if let Ok(i) = postings.binary_search_by_key(&(id as u32), |p| p.0) {
+                    postings.remove(i);
+                }
+            }
+        }
+        if let Some(new) = entry.as_ref() {
+            self.num_entries += 1;
+            for &(word, weight) in new.words.iter() {
+                // Posting lists are sorted by entry id. New entries go at the end.
+                let postings = &mut self.inverted[word as usize];
+                let i = postings.partition_point(|p| (p.0 as usize) < id);
+                postings.insert(i, (id as u32, weight));
+            }
+        }
+        self.entries[id] = entry;
+        self.mark_changed(id);
+        old
+    }
+
+    /// Record that an entry must be written by the next checkpoint.
+    fn mark_changed(&mut self, _id: EntryId) {
+        #[cfg(feature = "bincode")]
+        if let Some(file) = self.file.as_mut() {
+            file.changed.insert(_id);
+        }
+    }
+}
+
+/// Persistence
+#[cfg(feature = "bincode")]
+impl<M: Metadata> Database<M> {
+    /// Write the whole database to a file, replacing it. Later entries can be appended to the
+    /// file with [`Self::checkpoint`].
+    ///
+    /// The database is first written to a `.tmp` file next to `path`, which is then renamed
+    /// over it, so that an existing file is kept intact if writing fails.
+    pub fn save<P: AsRef<Path>>(&mut self, path: P) -> BowResult<()> {
+        let path = path.as_ref();
+        let mut tmp = path.as_os_str().to_owned();
+        tmp.push(".tmp");
+        let tmp = PathBuf::from(tmp);
+        let written = self.write_snapshot(&tmp).and_then(|len| {
+            std::fs::rename(&tmp, path)?;
+            Ok(len)
+        });
+        let len = match written {
+            Ok(len) => len,
+            Err(e) => {
+                let _ = std::fs::remove_file(&tmp);
+                return Err(e);
+            }
+        };
+        self.file = Some(DatabaseFile {
+            path: path.to_owned(),
+            len,
+            changed: BTreeSet::new(),
+        });
+        Ok(())
+    }
+
+    /// Write a new file holding the header and a snapshot of the database, and sync it.
+    /// Returns the length of the file.
+    fn write_snapshot(&self, path: &Path) -> BowResult<u64> {
+        let mut w = BufWriter::new(File::create(path)?);
+        w.write_all(MAGIC)?;
+        w.write_all(&VERSION.to_le_bytes())?;
+        let mut len = (MAGIC.len() + 4) as u64 + write_record(&mut w, &M::tag())?;
+        len += write_record(&mut w, self)?;
+        w.into_inner().map_err(|e| e.into_error())?.sync_data()?;
+        Ok(len)
+    }
+
+    /// Append the entries added, removed or replaced since the database was last saved, loaded
+    /// or checkpointed to its file. Returns the number of entries written, or Err if the
+    /// database has not been saved or loaded, or the file was truncated since.
+    pub fn checkpoint(&mut self) -> BowResult<usize> {
+        let file = self.file.as_mut().ok_or(BowErr::InvalidParams(
+            "database must be saved or loaded before checkpointing",
+        ))?;
+        if file.changed.is_empty() {
+            return Ok(0);
+        }
+        let mut f = OpenOptions::new().write(true).open(&file.path)?;
+        if f.metadata()?.len() < file.len {
+            return Err(BowErr::DatabaseFormat(format!(
+                "{:?} is shorter than when it was last written",
+                file.path
+            )));
+        }
+        // Drop an incomplete record left by an interrupted checkpoint
+        f.set_len(file.len)?;
+        f.seek(SeekFrom::End(0))?;
+        let mut w = BufWriter::new(f);
+        let mut len = file.len;
+        // Ids are written in increasing order, so new entries are appended in order on loading
+        for &id in file.changed.iter() {
+            len += write_record(&mut w, &(id, &self.entries[id]))?;
+        }
+        w.into_inner().map_err(|e| e.into_error())?.sync_data()?;
+        file.len = len;
+        Ok(std::mem::take(&mut file.changed).len())
+    }
+
+    /// Load a database saved with [`Self::save`], with the changes of its checkpoints.
+    /// Returns Err if the file is not a database, or `voc` is not its vocabulary.
+    pub fn load<P: AsRef<Path>>(path: P, voc: &Vocabulary) -> BowResult<Self> {
+        let path = path.as_ref();
+        let mut r = BufReader::new(File::open(path)?);
+        let mut header = [0; 12];
+        r.read_exact(&mut header)
+            .map_err(|_| BowErr::DatabaseFormat("missing header".to_owned()))?;
+        if &header[..8] != MAGIC {
+            return Err(BowErr::DatabaseFormat("not a database file".to_owned()));
+        }
+        let version = u32::from_le_bytes(header[8..].try_into().unwrap());
+        if version != VERSION {
+            return Err(BowErr::UnsupportedVersion {
+                kind: "Database",
+                version,
+            });
+        }
+        let (tag, tag_len) = read_record(&mut r)?
+            .ok_or_else(|| BowErr::DatabaseFormat("missing metadata tag".to_owned()))?;
+        let tag: String = bincode::deserialize(&tag)?;
+        if tag != M::tag() {
+            return Err(BowErr::DatabaseFormat(format!(
+                "file was saved with metadata {:?}, not {:?}",
+                tag,
+                M::tag()
+            )));
+        }
+
+        let (snapshot, snapshot_len) = read_record(&mut r)?
+            .ok_or_else(|| BowErr::DatabaseFormat("incomplete snapshot".to_owned()))?;
+        let snapshot: Snapshot<M> = bincode::deserialize(&snapshot)?;
+        let mut db = Database::try_from(snapshot)?;
+        db.check_vocabulary(voc)?;
+        if db.num_words != voc.num_words() {
+            return Err(BowErr::DatabaseFormat(
+                "number of words differs from the vocabulary".to_owned(),
+            ));
+        }
+        let mut len = header.len() as u64 + tag_len + snapshot_len;
+        while let Some((record, record_len)) = read_record(&mut r)? {
+            let (id, entry): (EntryId, Option<Entry<M>>) = bincode::deserialize(&record)?;
+            if id > db.entries.len() {
+                return Err(BowErr::DatabaseFormat("entry id out of range".to_owned()));
+            }
+            if let Some(entry) = entry.as_ref() {
+                check_words(&entry.words, db.num_words)?;
+            }
+            db.set(id, entry);
+            len += record_len;
+        }
+        db.file = Some(DatabaseFile {
+            path: path.to_owned(),
+            len,
+            changed: BTreeSet::new(),
+        });
+        Ok(db)
+    }
+}
+
+/// Returns Err unless the word ids of an entry are increasing and below `num_words`.
+fn check_words(words: &Words, num_words: usize) -> BowResult<()> {
+    let increasing = words.windows(2).all(|w| w[0].0 < w[1].0);
+    match increasing && words.last().map_or(true, |w| (w.0 as usize) < num_words) {
+        true => Ok(()),
+        false => Err(BowErr::DatabaseFormat("invalid word ids".to_owned())),
+    }
+}
+
+/// Nonzero weights of a BoW vector.
+fn sparse(bow: &BoW) -> Words {
+    bow.0
+        .iter()
+        .enumerate()
+        .filter(|(_, &w)| w != 0.)
+        .map(|(word, &w)| (word as u32, w))
+        .collect()
+}
+
+/// Write a length-prefixed record. Returns the number of bytes written.
+#[cfg(feature = "bincode")]
+fn write_record<W: Write, T: Serialize>(w: &mut W, value: &T) -> BowResult<u64> {
+    let data = bincode::serialize(value)?;
+    w.write_all(&(data.len() as u64).to_le_bytes())?;
+    w.write_all(&data)?;
+    Ok(8 + data.len() as u64)
+}
+
+/// Read a length-prefixed record and the number of bytes it took,
+/// or None at the end of the file or if the record is incomplete.
+#[cfg(feature = "bincode")]
+fn read_record<R: Read>(r: &mut R) -> BowResult<Option<(Vec<u8>, u64)>> {
+    let mut len = [0; 8];
+    let mut read = 0;
+    while read < len.len() {
+        match r.read(&mut len[read..])? {
+            0 => return Ok(None),
+            n => read += n,
+        }
+    }
+    let len = u64::from_le_bytes(len);
+    let mut data = Vec::new();
+    r.take(len).read_to_end(&mut data)?;
+    match data.len() as u64 == len {
+        true => Ok(Some((data, 8 + len))),
+        false => Ok(None),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use rand::{thread_rng, Rng};
+
+    /// A file name unique to this test run.
+    #[cfg(feature = "bincode")]
+    fn temp_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("abow_test_{}_{}.db", name, std::process::id()))
+    }
+
+    #[test]
+    fn query() {
+        let features: Vec<Desc> = (0..1000).map(|_| thread_rng().gen()).collect();
+        let voc = Vocabulary::create(&features, 4, 3).unwrap();
+        let mut db: Database = Database::new(&voc);
+        for image in features.chunks(100) {
+            db.add(&voc, image).unwrap();
+        }
+        assert_eq!(db.len(), 10);
+
+        let query = voc.transform(&features[200..300]).unwrap();
+        let results = db.query(&query, 3).unwrap();
+        assert_eq!(results.len(), 3);
+        assert_eq!(results[0].entry, 2);
+        for r in results.iter() {
+            let score = query.l1(&db.bow(r.entry).unwrap()).unwrap();
+            assert!((r.score - score).abs() < 1e-5);
+        }
+        assert!(matches!(
+            db.query(&BoW(vec![1.], None), 3),
+            Err(BowErr::DimensionMismatch { .. })
+        ));
+
+        let other = Vocabulary::create(&features, 4, 3).unwrap();
+        assert!(matches!(
+            db.add(&other, &features[..10]),
+            Err(BowErr::VocabularyMismatch { .. })
+        ));
+        let other_bow = other.transform(&features[..10]).unwrap();
+        assert!(matches!(
+            db.query(&other_bow, 3),
+            Err(BowErr::VocabularyMismatch { .. })
+        ));
+
+        // NaN weights leave the results ordered
+        let mut nan = query.clone();
+        for w in nan.0.iter_mut().step_by(2) {
+            *w = f32::NAN;
+        }
+        db.add_bow(&nan).unwrap();
+        for bow in [&query, &nan] {
+            let results = db.query(bow, 11).unwrap();
+            assert!(results.windows(2).all(|r| r[0].score >= r[1].score));
+        }
+    }
+
+    #[test]
+    fn filters() {
+        let features: Vec<Desc> = (0..1000).map(|_| thread_rng().gen()).collect();
+        let voc = Vocabulary::create(&features, 4, 3).unwrap();
+        let mut db: Database = Database::new(&voc);
+        for image in features.chunks(50) {
+            db.add(&voc, image).unwrap();
+        }
+        let query = voc.transform(&features[500..550]).unwrap();
+        let all = db.query(&query, 20).unwrap();
+        assert_eq!(all[0].entry, 10);
+
+        // Skipping the last entries, and overlapping ranges
+        let recent = QueryOptions {
+            max_results: 20,
+            filter: QueryFilter::Ranges(vec![12..15, 0..10, 5..8]),
+            ..Default::default()
+        };
+        let results = db.query_with(&query, &recent).unwrap();
+        let expected: Vec<_> = all
+            .iter()
+            .filter(|r| r.entry < 10 || (12..15).contains(&r.entry))
+            .collect();
+        assert_eq!(results.len(), expected.len());
+        assert!(results.iter().zip(expected).all(|(a, b)| a == b));
+
+        let calls = std::cell::Cell::new(0);
+        let even = |entry: EntryId, _: &()| {
+            calls.set(calls.get() + 1);
+            entry % 2 == 0
+        };
+        let options = QueryOptions {
+            max_results: 20,
+            filter: QueryFilter::Fn(&even),
+            ..Default::default()
+        };
+        let results = db.query_with(&query, &options).unwrap();
+        assert!(results.iter().all(|r| r.entry % 2 == 0));
+        assert_eq!(
+            results.len(),
+            all.iter().filter(|r| r.entry % 2 == 0).count()
+        );
+        assert!(calls.get() <= db.len());
+
+        // Nothing else is similar to the query
+        let min_score = QueryOptions {
+            min_score: 0.9,
+            ..Default::default()
+        };
+        assert_eq!(db.query_with(&query, &min_score).unwrap(), all[..1]);
+    }
+
+    #[test]
+    fn metadata() {
+        let features: Vec<Desc> = (0..1000).map(|_| thread_rng().gen()).collect();
+        let voc = Vocabulary::create(&features, 4, 3).unwrap();
+        // (session, timestamp) of each keyframe
+        let mut db = Database::new(&voc);
+        for (i, image) in features.chunks(100).enumerate() {
+            db.add_with_metadata(&voc, image, (i / 5, i as f64 * 0.5))
+                .unwrap();
+        }
+        let query = voc.transform(&features[700..800]).unwrap();
+        let best = &db.query(&query, 1).unwrap()[0];
+        assert_eq!((best.entry, best.metadata), (7, (1, 3.5)));
+
+        // Only other sessions
+        let other_session = |_: EntryId, m: &(usize, f64)| m.0 != 1;
+        let options = QueryOptions {
+            filter: QueryFilter::Fn(&other_session),
+            ..Default::default()
+        };
+        let results = db.query_with(&query, &options).unwrap();
+        assert!(!results.is_empty() && results.iter().all(|r| r.entry < 5));
+        assert!(results
+            .iter()
+            .all(|r| Some(&r.metadata) == db.metadata(r.entry)));
+
+        // Replacing keeps the metadata
+        db.replace_features(7, &voc, &features[..100]).unwrap();
+        assert_eq!(db.metadata(7), Some(&(1, 3.5)));
+        db.set_metadata(7, (2, 0.)).unwrap();
+        assert_eq!(db.metadata(7), Some(&(2, 0.)));
+        assert!(db.set_metadata(10, (0, 0.)).is_err());
+
+        #[cfg(feature = "bincode")]
+        {
+            let path = temp_path("metadata");
+            db.save(&path).unwrap();
+            db.set_metadata(3, (3, 1.)).unwrap();
+            db.add_bow_with_metadata(&query, (3, 2.)).unwrap();
+            assert_eq!(db.checkpoint().unwrap(), 2);
+            let loaded: Database<(usize, f64)> = Database::load(&path, &voc).unwrap();
+            for entry in 0..11 {
+                assert_eq!(loaded.metadata(entry), db.metadata(entry));
+            }
+
+            // Bincode would read the entries with other metadata types without noticing
+            assert!(matches!(
+                Database::<()>::load(&path, &voc),
+                Err(BowErr::DatabaseFormat(_))
+            ));
+            assert!(Database::<(f64, usize)>::load(&path, &voc).is_err());
+
+            // Types with the same tag read each other's files
+            #[derive(Serialize, Deserialize)]
+            struct Keyframe(usize, f64);
+            impl Metadata for Keyframe {
+                fn tag() -> String {
+                    <(usize, f64)>::tag()
+                }
+            }
+            let keyframes: Database<Keyframe> = Database::load(&path, &voc).unwrap();
+            assert_eq!(keyframes.metadata(3).map(|k| (k.0, k.1)), Some((3, 1.)));
+
+            // Files of other versions are refused
+            let mut bytes = std::fs::read(&path).unwrap();
+            bytes[8..12].copy_from_slice(&2u32.to_le_bytes());
+            std::fs::write(&path, bytes).unwrap();
+            assert!(matches!(
+                Database::<(usize, f64)>::load(&path, &voc),
+                Err(BowErr::UnsupportedVersion { version: 2, .. })
+            ));
+            std::fs::remove_file(&path).unwrap();
+        }
+    }
+
+    #[test]
+    fn remove_and_replace() {
+        let features: Vec<Desc> = (0..1000).map(|_| thread_rng().gen()).collect();
+        let voc = Vocabulary::create(&features, 4, 3).unwrap();
+        let mut db: Database = Database::with_direct_index(&voc);
+        for image in features.chunks(100) {
+            db.add(&voc, image).unwrap();
+        }
+        let query = voc.transform(&features[200..300]).unwrap();
+
+        db.remove(2).unwrap();
+        assert!(matches!(db.remove(2), Err(BowErr::UnknownEntry(2))));
+        assert_eq!((db.len(), db.bow(2), db.direct_index(2)), (9, None, None));
+        assert_eq!(db.next_id(), 10);
+        let results = db.query(&query, 10).unwrap();
+        assert!(!results.is_empty() && results.iter().all(|r| r.entry != 2));
+
+        // Entry 5 now holds the image of entry 2
+        db.replace_features(5, &voc, &features[200..300]).unwrap();
+        assert_eq!(db.query(&query, 1).unwrap()[0].entry, 5);
+        assert!(db.direct_index(5).is_some());
+        assert!(db.replace(5, &query).is_err());
+        assert!(db.replace_features(2, &voc, &features[..10]).is_err());
+
+        // Skipping the last 6 ids leaves entries 0, 1 and 3
+        let older = 0..db.next_id().saturating_sub(6);
+        let older = QueryOptions {
+            filter: QueryFilter::Ranges(vec![older]),
+            ..Default::default()
+        };
+        let results = db.query_with(&query, &older).unwrap();
+        assert!(!results.is_empty() && results.iter().all(|r| [0, 1, 3].contains(&r.entry)));
+
+        let ids = db.compact().unwrap();
+        assert_eq!(ids[2], None);
+        assert_eq!(ids[5], Some(4));
+        assert_eq!(db.ids().collect::<Vec<_>>(), (0..9).collect::<Vec<_>>());
+        assert_eq!(db.next_id(), 9);
+        let best = db.query(&query, 1).unwrap()[0];
+        assert_eq!(best.entry, 4);
+        assert!((best.score - 1.).abs() < 1e-5);
+        assert!(db.inverted.iter().flatten().all(|p| (p.0 as usize) < 9));
+    }
+
+    #[cfg(feature = "bincode")]
+    #[test]
+    fn deserialize() {
+        let features: Vec<Desc> = (0..500).map(|_| thread_rng().gen()).collect();
+        let voc = Vocabulary::create(&features, 4, 3).unwrap();
+        let mut db: Database = Database::new(&voc);
+        for image in features.chunks(100) {
+            db.add(&voc, image).unwrap();
+        }
+        db.remove(2).unwrap();
+        let copy: Database = bincode::deserialize(&bincode::serialize(&db).unwrap()).unwrap();
+        assert_eq!(copy.ids().collect::<Vec<_>>(), [0, 1, 3, 4]);
+        assert_eq!(copy.bow(3), db.bow(3));
+
+        // Deserializing checks the index like loading does, so queries cannot panic
+        let mut corrupt = db.clone();
+        corrupt.inverted[0].push((2, 0.5));
+        let bytes = bincode::serialize(&corrupt).unwrap();
+        assert!(bincode::deserialize::<Database>(&bytes).is_err());
+        let mut corrupt = db;
+        corrupt.inverted.pop();
+        let bytes = bincode::serialize(&corrupt).unwrap();
+        assert!(bincode::deserialize::<Database>(&bytes).is_err());
+    }
+
+    #[cfg(feature = "bincode")]
+    #[test]
+    fn save_and_checkpoint() {
+        let features: Vec<Desc> = (0..1000).map(|_| thread_rng().gen()).collect();
+        let voc = Vocabulary::create(&features, 4, 3).unwrap();
+        let path = temp_path("checkpoint");
+        let mut db: Database = Database::with_direct_index(&voc);
+        assert!(db.checkpoint().is_err());
+        for image in features.chunks(100).take(6) {
+            db.add(&voc, image).unwrap();
+        }
+        db.save(&path).unwrap();
+        for image in features.chunks(100).skip(6) {
+            db.add(&voc, image).unwrap();
+        }
+        assert_eq!(db.checkpoint().unwrap(), 4);
+        assert_eq!(db.checkpoint().unwrap(), 0);
+
+        // An interrupted checkpoint leaves an incomplete record, which is skipped and overwritten
+        let mut f = OpenOptions::new().append(true).open(&path).unwrap();
+        f.write_all(&[200, 0, 0, 0, 0, 0, 0, 0, 1, 2, 3]).unwrap();
+        let mut loaded: Database = Database::load(&path, &voc).unwrap();
+        assert_eq!(loaded.len(), 10);
+        for entry in 0..10 {
+            assert_eq!(loaded.bow(entry), db.bow(entry));
+            assert_eq!(loaded.direct_index(entry), db.direct_index(entry));
+        }
+        let query = voc.transform(&features[700..800]).unwrap();
+        assert_eq!(
+            loaded.query(&query, 5).unwrap(),
+            db.query(&query, 5).unwrap()
+        );
+
+        loaded.add(&voc, &features[..50]).unwrap();
+        assert_eq!(loaded.checkpoint().unwrap(), 1);
+        assert_eq!(Database::<()>::load(&path, &voc).unwrap().len(), 11);
+
+        // Removals and replacements are checkpointed too, and compaction rewrites the file
+        loaded.remove(3).unwrap();
+        loaded.replace_features(4, &voc, &features[..30]).unwrap();
+        let new = loaded.add(&voc, &features[30..60]).unwrap();
+        loaded.remove(new).unwrap();
+        assert_eq!(loaded.checkpoint().unwrap(), 3);
+        let reloaded: Database = Database::load(&path, &voc).unwrap();
+        assert_eq!(
+            reloaded.ids().collect::<Vec<_>>(),
+            loaded.ids().collect::<Vec<_>>()
+        );
+        assert_eq!(reloaded.bow(4), loaded.bow(4));
+        let len = std::fs::metadata(&path).unwrap().len();
+        loaded.compact().unwrap();
+        assert!(std::fs::metadata(&path).unwrap().len() < len);
+        assert_eq!(Database::<()>::load(&path, &voc).unwrap().len(), 10);
+
+        let other = Vocabulary::create(&features, 4, 3).unwrap();
+        assert!(matches!(
+            Database::<()>::load(&path, &other),
+            Err(BowErr::VocabularyMismatch { .. })
+        ));
+
+        // Snapshots whose index does not match their entries are refused
+        let mut corrupt = loaded.clone();
+        corrupt.inverted[0].push((100, 0.5));
+        corrupt.save(&path).unwrap();
+        assert!(matches!(
+            Database::<()>::load(&path, &voc),
+            Err(BowErr::DatabaseFormat(_))
+        ));
+        let mut corrupt = loaded.clone();
+        corrupt.num_entries += 1;
+        corrupt.save(&path).unwrap();
+        assert!(Database::<()>::load(&path, &voc).is_err());
+        let mut corrupt = loaded;
+        corrupt.entries[0]
+            .as_mut()
+            .unwrap()
+            .words
+            .push((u32::MAX, 0.5));
+        corrupt.save(&path).unwrap();
+        assert!(Database::<()>::load(&path, &voc).is_err());
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[cfg(feature = "bincode")]
+    #[test]
+    fn failed_compaction() {
+        let features: Vec<Desc> = (0..600).map(|_| thread_rng().gen()).collect();
+        let voc = Vocabulary::create(&features, 4, 3).unwrap();
+        let path = temp_path("compaction");
+        let mut db: Database = Database::new(&voc);
+        for image in features.chunks(100).take(5) {
+            db.add(&voc, image).unwrap();
+        }
+        db.save(&path).unwrap();
+        db.remove(1).unwrap();
+        let bows: Vec<_> = (0..5).map(|entry| db.bow(entry)).collect();
+
+        // The temporary file cannot be created over a directory
+        let mut tmp = path.clone().into_os_string();
+        tmp.push(".tmp");
+        std::fs::create_dir(&tmp).unwrap();
+        assert!(db.compact().is_err());
+        std::fs::remove_dir(&tmp).unwrap();
+        assert_eq!(db.ids().collect::<Vec<_>>(), [0, 2, 3, 4]);
+        assert_eq!((0..5).map(|entry| db.bow(entry)).collect::<Vec<_>>(), bows);
+        let query = voc.transform(&features[300..400]).unwrap();
+        assert_eq!(db.query(&query, 1).unwrap()[0].entry, 3);
+
+        // The file was left as it was, so checkpoints still apply to it
+        db.add(&voc, &features[500..]).unwrap();
+        assert_eq!(db.checkpoint().unwrap(), 2);
+        let loaded: Database = Database::load(&path, &voc).unwrap();
+        assert_eq!(loaded.ids().collect::<Vec<_>>(), [0, 2, 3, 4, 5]);
+        assert_eq!(loaded.bow(3), bows[3]);
+
+        assert_eq!(
+            db.compact().unwrap(),
+            [Some(0), None, Some(1), Some(2), Some(3), Some(4)]
+        );
+        let loaded: Database = Database::load(&path, &voc).unwrap();
+        assert_eq!(loaded.ids().collect::<Vec<_>>(), [0, 1, 2, 3, 4]);
+        assert_eq!(loaded.bow(2), bows[3]);
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,397 @@
+//! Containers for descriptors grouped per image, for exchanging features with
+//! other pipelines (e.g. Python or C++) without OpenCV.
+//!
+//! Two equivalent formats are supported. [`read`](crate::descriptor_file::read) detects which
+//! one a file uses.
+//!
+//! **Binary** (all integers and floats little-endian):
+//! ```text
+//! magic        8 bytes   b"ABOWDESC"
+//! version      u32       1
+//! num_images   u32
+//! per image:
+//!   name_len   u32
+//!   name       name_len bytes of UTF-8
+//!   num_desc   u32
+//!   flags      u8        bit 0 set if keypoints are present
+//!   descs      num_desc * 32 bytes
+//!   keypoints  num_desc * 2 f32 (x, y), only if flagged
+//! ```
+//!
+//! **Text**: the first line is the header `# abow descriptors v1`. Each image starts with a
+//! line `image <name>`, followed by one line per descriptor: 64 hex digits, optionally
+//! followed by the keypoint's `x y` coordinates. Either all or none of an image's descriptors
+//! have coordinates. Blank lines and lines starting with `#` are ignored.
+//! ```text
+//! # abow descriptors v1
+//! image 0.jpg
+//! 9f3a...e1 120.5 64.0
+//! 07bc...5d 88.0 19.25
+//! ```
+use crate::*;
+use std::{
+    convert::TryInto,
+    fs::File,
+    io::{BufRead, BufReader, BufWriter, Read, Write},
+    path::Path,
+};
+
+const MAGIC: &[u8; 8] = b"ABOWDESC";
+const VERSION: u32 = 1;
+const TEXT_HEADER: &str = "# abow descriptors v1";
+
+#[derive(Debug, Clone, PartialEq, Default)]
+/// Descriptors extracted from a single image.
+pub struct ImageDescriptors {
+    /// Image name or path.
+    pub name: String,
+    /// Binary descriptors of the image's keypoints.
+    pub descriptors: Vec<Desc>,
+    /// Optional (x, y) position of each descriptor's keypoint, in pixels.
+    pub keypoints: Option<Vec<[f32; 2]>>,
+}
+
+/// Read a descriptor file in either format.
+pub fn read<P: AsRef<Path>>(path: P) -> BowResult<Vec<ImageDescriptors>> {
+    let mut reader = BufReader::new(File::open(path)?);
+    if reader.fill_buf()?.starts_with(MAGIC) {
+        read_binary(reader)
+    } else {
+        read_text(reader)
+    }
+}
+
+/// Returns true if the file starts like a descriptor file of either format.
+pub fn is_descriptor_file<P: AsRef<Path>>(path: P) -> bool {
+    // Binary files without images are shorter than the text header
+    let mut start = Vec::new();
+    let read =
+        File::open(path).and_then(|f| f.take(TEXT_HEADER.len() as u64).read_to_end(&mut start));
+    match read {
+        Ok(_) => start.starts_with(MAGIC) || start == TEXT_HEADER.as_bytes(),
+        Err(_) => false,
+    }
+}
+
+/// Write images to a file in the binary format. Returns Err without creating the file if an
+/// image does not have one keypoint per descriptor.
+pub fn write_binary<P: AsRef<Path>>(path: P, images: &[ImageDescriptors]) -> BowResult<()> {
+    for image in images {
+        image.check()?;
+    }
+    let mut w = BufWriter::new(File::create(path)?);
+    w.write_all(MAGIC)?;
+    w.write_all(&VERSION.to_le_bytes())?;
+    w.write_all(&(images.len() as u32).to_le_bytes())?;
+    for image in images {
+        w.write_all(&(image.name.len() as u32).to_le_bytes())?;
+        w.write_all(image.name.as_bytes())?;
+        w.write_all(&(image.descriptors.len() as u32).to_le_bytes())?;
+        w.write_all(&[image.keypoints.is_some() as u8])?;
+        for d in image.descriptors.iter() {
+            w.write_all(d)?;
+        }
+        for [x, y] in image.keypoints.iter().flatten() {
+            w.write_all(&x.to_le_bytes())?;
+            w.write_all(&y.to_le_bytes())?;
+        }
+    }
+    w.flush()?;
+    Ok(())
+}
+
+/// Write images to a file in the text format. Returns Err without creating the file if an
+/// image does not have one keypoint per descriptor.
+pub fn write_text<P: AsRef<Path>>(path: P, images: &[ImageDescriptors]) -> BowResult<()> {
+    for image in images {
+        image.check()?;
+    }
+    let mut w = BufWriter::new(File::create(path)?);
+    writeln!(w, "{}", TEXT_HEADER)?;
+    for image in images {
+        writeln!(w, "image {}", image.name)?;
+        for (i, d) in image.descriptors.iter().enumerate() {
+            for byte in d {
+                write!(w, "{:02x}", byte)?;
+            }
+            if let Some(kps) = &image.keypoints {
+                write!(w, " {} {}", kps[i][0], kps[i][1])?;
+            }
+            writeln!(w)?;
+        }
+    }
+    w.flush()?;
+    Ok(())
+}
+
+/// Read images in the binary format.
+pub fn read_binary<R: Read>(mut r: R) -> BowResult<Vec<ImageDescriptors>> {
+    let mut magic = [0; 8];
+    r.read_exact(&mut magic)?;
+    if &magic != MAGIC {
+        return Err(format_err("missing binary header"));
+    }
+    let version = read_u32(&mut r)?;
+    if version != VERSION {
+        return Err(format_err(format!("unsupported version {}", version)));
+    }
+
+    let num_images = read_u32(&mut r)?;
+    let mut images = Vec::new();
+    for _ in 0..num_images {
+        let name_len = read_u32(&mut r)?;
+        let name = read_bytes(&mut r, name_len as u64)?;
+        let name = String::from_utf8(name).map_err(|_| format_err("image name is not UTF-8"))?;
+
+        let num_desc = read_u32(&mut r)? as u64;
+        let mut flags = [0];
+        r.read_exact(&mut flags)?;
+
+        let descriptors = read_bytes(&mut r, num_desc * 32)?
+            .chunks_exact(32)
+            .map(|d| d.try_into().unwrap())
+            .collect();
+        let keypoints = if flags[0] & 1 != 0 {
+            let kps = read_bytes(&mut r, num_desc * 8)?
+                .chunks_exact(8)
+                .map(|kp| {
+                    [
+                        f32::from_le_bytes(kp[..4].try_into().unwrap()),
+                        f32::from_le_bytes(kp[4..].try_into().unwrap()),
+                    ]
+                })
+                .collect();
+            Some(kps)
+        } else {
+            None
+        };
+
+        images.push(ImageDescriptors {
+            name,
+            descriptors,
+            keypoints,
+        });
+    }
+    Ok(images)
+}
+
+/// Read images in the text format.
+pub fn read_text<R: BufRead>(r: R) -> BowResult<Vec<ImageDescriptors>> {
+    let mut lines = r.lines().enumerate();
+    let header = lines.next().map(|(_, line)| line).transpose()?;
+    if header.as_deref().map(str::trim_end) != Some(TEXT_HEADER) {
+        return Err(format_err("missing text header"));
+    }
+
+    let mut images: Vec<ImageDescriptors> = Vec::new();
+    for (n, line) in lines {
+        let line = line?;
+        let line = line.trim();
+        let err = |msg: &str| format_err(format!("line {}: {}", n + 1, msg));
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        if let Some(name) = line.strip_prefix("image ") {
+            images.push(ImageDescriptors {
+                name: name.trim().to_owned(),
+                ..Default::default()
+            });
+            continue;
+        }
+
+        let image = images
+            .last_mut()
+            .ok_or_else(|| err("descriptor before first image"))?;
+        let mut fields = line.split_whitespace();
+        let hex = fields.next().unwrap();
+        image
+            .descriptors
+            .push(parse_hex(hex).ok_or_else(|| err("invalid descriptor"))?);
+
+        let coords: Vec<&str> = fields.collect();
+        let keypoint = match coords[..] {
+            [] => None,
+            [x, y] => match (x.parse(), y.parse()) {
+                (Ok(x), Ok(y)) => Some([x, y]),
+                _ => return Err(err("invalid keypoint coordinates")),
+            },
+            _ => return Err(err("expected a descriptor and optional x y coordinates")),
+        };
+        match (image.descriptors.len(), &mut image.keypoints, keypoint) {
+            (1, kps, Some(kp)) => *kps = Some(vec![kp]),
+            (_, Some(kps), Some(kp)) => kps.push(kp),
+            (_, None, None) => {}
+            _ => {
+                return Err(err(
+                    "all or none of an image's descriptors need coordinates",
+                ))
+            }
+        }
+    }
+    Ok(images)
+}
+
+impl ImageDescriptors {
+    /// Check that there is one keypoint per descriptor, if any.
+    fn check(&self) -> BowResult<()> {
+        match &self.keypoints {
+            Some(kps) if kps.len() != self.descriptors.len() => Err(format_err(format!(
+                "{} has {} descriptors but {} keypoints",
+                self.name,
+                self.descriptors.len(),
+                kps.len()
+            ))),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Vocabulary helpers for descriptor files
+impl Vocabulary {
+    /// Build a vocabulary from all descriptors in a descriptor file. See [`Vocabulary::create`].
+    pub fn create_from_file<P: AsRef<Path>>(path: P, k: usize, l: usize) -> BowResult<Self> {
+        let features: Vec<Desc> = read(path)?
+            .into_iter()
+            .flat_map(|i| i.descriptors)
+            .collect();
+        Self::create(&features, k, l)
+    }
+
+    /// Transform each image in a descriptor file into its bag of words representation.
+    /// Returns Err if any image has no descriptors.
+    pub fn transform_file<P: AsRef<Path>>(&self, path: P) -> BowResult<Vec<(String, BoW)>> {
+        read(path)?
+            .into_iter()
+            .map(|image| Ok((image.name, self.transform(&image.descriptors)?)))
+            .collect()
+    }
+}
+
+fn read_u32<R: Read>(r: &mut R) -> BowResult<u32> {
+    let mut buf = [0; 4];
+    r.read_exact(&mut buf)?;
+    Ok(u32::from_le_bytes(buf))
+}
+
+/// Read exactly `len` bytes. Memory only grows with the bytes actually read, so a corrupt
+/// length in a truncated file is an error rather than a huge allocation.
+fn read_bytes<R: Read>(r: &mut R, len: u64) -> BowResult<Vec<u8>> {
+    let mut data = Vec::new();
+    r.take(len).read_to_end(&mut data)?;
+    match data.len() as u64 == len {
+        true => Ok(data),
+        false => Err(format_err("unexpected end of file")),
+    }
+}
+
+fn parse_hex(hex: &str) -> Option<Desc> {
+    // from_str_radix would also accept a sign
+    if hex.len() != 64 || !hex.bytes().all(|b| b.is_ascii_hexdigit()) {
+        return None;
+    }
+    let mut desc = [0; 32];
+    for (i, byte) in desc.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(&hex[2 * i..2 * i + 2], 16).ok()?;
+    }
+    Some(desc)
+}
+
+fn format_err<S: Into<String>>(msg: S) -> BowErr {
+    BowErr::DescriptorFormat(msg.into())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use rand::{thread_rng, Rng};
+
+    fn images() -> Vec<ImageDescriptors> {
+        let mut rng = thread_rng();
+        vec![
+            ImageDescriptors {
+                name: "first.jpg".to_owned(),
+                descriptors: (0..200).map(|_| rng.gen()).collect(),
+                keypoints: Some((0..200).map(|i| [i as f32, 0.5]).collect()),
+            },
+            ImageDescriptors {
+                name: "dir/second image.png".to_owned(),
+                descriptors: (0..100).map(|_| rng.gen()).collect(),
+                keypoints: None,
+            },
+        ]
+    }
+
+    #[test]
+    fn round_trip() {
+        let images = images();
+        let dir = std::env::temp_dir();
+        let name = format!("abow_test_{}", std::process::id());
+        let (bin, text) = (
+            dir.join(format!("{}.abd", name)),
+            dir.join(format!("{}.txt", name)),
+        );
+        write_binary(&bin, &images).unwrap();
+        write_text(&text, &images).unwrap();
+        assert!(is_descriptor_file(&bin) && is_descriptor_file(&text));
+        assert_eq!(read(&bin).unwrap(), images);
+        assert_eq!(read(&text).unwrap(), images);
+
+        let voc = Vocabulary::create_from_file(&bin, 4, 2).unwrap();
+        let bows = voc.transform_file(&text).unwrap();
+        assert_eq!(bows[1].0, "dir/second image.png");
+        assert_eq!(bows[1].1, voc.transform(&images[1].descriptors).unwrap());
+
+        // Nothing is written if an image is invalid
+        let mut invalid = images;
+        invalid[0].keypoints.as_mut().unwrap().pop();
+        std::fs::remove_file(&bin).unwrap();
+        std::fs::remove_file(&text).unwrap();
+        assert!(write_binary(&bin, &invalid).is_err() && !bin.exists());
+        assert!(write_text(&text, &invalid).is_err() && !text.exists());
+
+        // A binary file without images is shorter than the text header
+        write_binary(&bin, &[]).unwrap();
+        assert!(is_descriptor_file(&bin));
+        assert_eq!(read(&bin).unwrap(), []);
+        std::fs::remove_file(&bin).unwrap();
+    }
+
+    #[test]
+    fn truncated_binary() {
+        let mut data = Vec::new();
+        data.extend_from_slice(MAGIC);
+        data.extend_from_slice(&VERSION.to_le_bytes());
+        data.extend_from_slice(&1_u32.to_le_bytes());
+        data.extend_from_slice(&1_u32.to_le_bytes());
+        data.push(b'a');
+        // Claims 4 billion descriptors but holds one
+        data.extend_from_slice(&u32::MAX.to_le_bytes());
+        data.push(1);
+        data.extend_from_slice(&[7; 32]);
+        assert!(matches!(
+            read_binary(&data[..]),
+            Err(BowErr::DescriptorFormat(_))
+        ));
+
+        // And an image name of 4 GB
+        data[16..20].copy_from_slice(&u32::MAX.to_le_bytes());
+        assert!(read_binary(&data[..]).is_err());
+    }
+
+    #[test]
+    fn malformed_text() {
+        let parse = |s: &str| read_text(s.as_bytes());
+        let desc = "ab".repeat(32);
+        assert!(parse(&format!("{}\nimage a\n{}\n", TEXT_HEADER, desc)).is_ok());
+        assert!(parse(&format!("image a\n{}\n", desc)).is_err());
+        assert!(parse(&format!("{}\n{}\n", TEXT_HEADER, desc)).is_err());
+        assert!(parse(&format!("{}\nimage a\n{}\n", TEXT_HEADER, &desc[2..])).is_err());
+        let signed = format!("+f{}", &desc[2..]);
+        assert!(parse(&format!("{}\nimage a\n{}\n", TEXT_HEADER, signed)).is_err());
+        assert!(parse(&format!(
+            "{}\nimage a\n{} 1 2\n{}\n",
+            TEXT_HEADER, desc, desc
+        ))
+        .is_err());
+    }
+}
@@ -0,0 +1,139 @@
+//! Per-descriptor signatures for rejecting false word matches, after Jégou et al.,
+//! "Hamming embedding and weak geometric consistency".
+//!
+//! Unlike the paper's Hamming embedding, which projects descriptors and thresholds them at
+//! per-word medians learned during training, a signature here is simply the first
+//! [`SIGNATURE_BITS`](crate::embedding::SIGNATURE_BITS) bits of the descriptor's residual (XOR) relative to its word centroid.
+//! No extra training data is stored in the vocabulary, but the signature distance only
+//! reflects those leading bits of the descriptors.
+//!
+//! [`Database`] does not store signatures. To re-rank its query results, keep the
+//! [`EmbeddedBoW`] of each entry and compare candidates with [`EmbeddedBoW::score`].
+use crate::*;
+use std::convert::TryInto;
+
+/// Short binary signature of a descriptor's position within its word's cluster: the leading
+/// bits of its residual relative to the word centroid.
+pub type Signature = u64;
+
+/// Number of bits in a [`Signature`].
+pub const SIGNATURE_BITS: u32 = Signature::BITS;
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+/// The word a single descriptor was assigned to, with its residual signature.
+pub struct WordSignature {
+    /// Word/leaf id in the vocabulary.
+    pub word: u32,
+    /// Leading bits of the descriptor's residual relative to the word centroid.
+    pub signature: Signature,
+    /// Weight of the word in the vocabulary.
+    pub weight: f32,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+/// Bag-of-Words representation which also keeps a residual signature for every descriptor.
+/// See the [module documentation](self).
+///
+/// Produced by [`Vocabulary::transform_with_signatures`].
+pub struct EmbeddedBoW {
+    /// The usual BoW vector of the descriptors.
+    pub bow: BoW,
+    /// One entry per descriptor, sorted by word id.
+    pub signatures: Vec<WordSignature>,
+}
+
+impl EmbeddedBoW {
+    /// Similarity between two images, counting only the descriptor pairs which fall
+    /// into the same word and whose signatures differ by at most `threshold` bits.
+    ///
+    /// Each accepted pair contributes the product of the word weights, and the total is
+    /// divided by the L2 norms of both (unnormalized) BoW vectors. The result is in `[0, 1]`
+    /// for a pair of distinct images, and with `threshold >= SIGNATURE_BITS` it is the cosine
+    /// similarity of the BoW vectors, weighted by word frequency.
+    pub fn score(&self, other: &Self, threshold: u32) -> f32 {
+        let norm = |s: &Self| {
+            s.words()
+                .map(|w| w.iter().map(|ws| ws.weight).sum::<f32>().powi(2))
+                .sum::<f32>()
+                .sqrt()
+        };
+        let norms = norm(self) * norm(other);
+        if norms == 0. {
+            return 0.;
+        }
+
+        let mut score = 0.;
+        let (mut a, mut b) = (self.words().peekable(), other.words().peekable());
+        while let (Some(wa), Some(wb)) = (a.peek(), b.peek()) {
+            match wa[0].word.cmp(&wb[0].word) {
+                std::cmp::Ordering::Less => {
+                    a.next();
+                }
+                std::cmp::Ordering::Greater => {
+                    b.next();
+                }
+                std::cmp::Ordering::Equal => {
+                    for x in wa.iter() {
+                        for y in wb.iter() {
+                            if (x.signature ^ y.signature).count_ones() <= threshold {
+                                score += x.weight * y.weight;
+                            }
+                        }
+                    }
+                    a.next();
+                    b.next();
+                }
+            }
+        }
+
+        score / norms
+    }
+
+    /// Iterate over the signatures grouped by word.
+    fn words(&self) -> impl Iterator<Item = &[WordSignature]> {
+        let mut rest = &self.signatures[..];
+        std::iter::from_fn(move || {
+            let word = rest.first()?.word;
+            let len = rest.iter().take_while(|s| s.word == word).count();
+            let (group, tail) = rest.split_at(len);
+            rest = tail;
+            Some(group)
+        })
+    }
+}
+
+/// Signature of a descriptor relative to the centroid of its word: the first
+/// [`SIGNATURE_BITS`] bits of their XOR. Since descriptors are compared within a word, the
+/// centroid cancels out, and the distance between two signatures is the Hamming distance
+/// between the first bits of the descriptors. It is a truncated residual, not a projection,
+/// so how well it separates descriptors depends on the extractor's bit order.
+pub(crate) fn residual_signature(feature: &Desc, centroid: &Desc) -> Signature {
+    let mut residual = [0_u8; 8];
+    for (r, (f, c)) in residual.iter_mut().zip(feature.iter().zip(centroid)) {
+        *r = f ^ c;
+    }
+    Signature::from_be_bytes(residual[..].try_into().unwrap())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use rand::{thread_rng, Rng};
+
+    #[test]
+    fn signature_filtering() {
+        let features: Vec<Desc> = (0..2000).map(|_| thread_rng().gen()).collect();
+        let voc = Vocabulary::create(&features, 6, 3).unwrap();
+
+        let a = voc.transform_with_signatures(&features[..300]).unwrap();
+        let b = voc.transform_with_signatures(&features[300..600]).unwrap();
+        assert_eq!(a.bow, voc.transform(&features[..300]).unwrap());
+        assert_eq!(a.signatures.len(), 300);
+
+        // Without a threshold this is plain cosine similarity
+        assert!((a.score(&a, SIGNATURE_BITS) - 1.).abs() < 1e-5);
+        // Signatures reject matches between unrelated descriptors
+        assert!(a.score(&b, 16) < a.score(&b, SIGNATURE_BITS));
+        assert!(a.score(&a, 0) > a.score(&b, 0));
+    }
+}
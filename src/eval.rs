@@ -0,0 +1,368 @@
+//! Retrieval evaluation: precision-recall curves, recall@k, mean average precision and the
+//! area under the precision-recall curve, for any scoring function between BoW vectors.
+//!
+//! Each query is scored against every database entry. Entries are relevant to a query
+//! according to a [`GroundTruth`], which may also mark some pairs as ignored, e.g. an image
+//! matching itself. Queries without any relevant entry only count towards the PR curve.
+use std::io::Write;
+
+use serde::{Deserialize, Serialize};
+
+use crate::*;
+
+#[derive(Debug, Clone, PartialEq)]
+/// Which database entries are correct matches of each query.
+pub enum GroundTruth {
+    /// `relevant[q][d]` is true if entry `d` matches query `q`.
+    Matrix(Vec<Vec<bool>>),
+    /// Queries and entries have indices in a sequence (e.g. frame numbers), and match if their
+    /// indices differ by at most `tolerance`. Pairs which differ by at most `ignore_within` are
+    /// left out entirely, e.g. `Some(0)` when the queries are also in the database.
+    Sequence {
+        queries: Vec<i64>,
+        database: Vec<i64>,
+        tolerance: i64,
+        ignore_within: Option<i64>,
+    },
+    /// Queries and entries have positions, and match if they are at most `radius` apart.
+    /// Pairs at most `ignore_within` apart are left out entirely.
+    Positions {
+        queries: Vec<[f64; 3]>,
+        database: Vec<[f64; 3]>,
+        radius: f64,
+        ignore_within: Option<f64>,
+    },
+}
+
+impl GroundTruth {
+    /// Whether entry `d` matches query `q`, or None if the pair is ignored.
+    pub fn relevant(&self, q: usize, d: usize) -> Option<bool> {
+        match self {
+            GroundTruth::Matrix(m) => Some(m[q][d]),
+            GroundTruth::Sequence {
+                queries,
+                database,
+                tolerance,
+                ignore_within,
+            } => {
+                let diff = (queries[q] - database[d]).abs();
+                match ignore_within {
+                    Some(ignore) if diff <= *ignore => None,
+                    _ => Some(diff <= *tolerance),
+                }
+            }
+            GroundTruth::Positions {
+                queries,
+                database,
+                radius,
+                ignore_within,
+            } => {
+                let (a, b) = (queries[q], database[d]);
+                let dist =
+                    ((a[0] - b[0]).powi(2) + (a[1] - b[1]).powi(2) + (a[2] - b[2]).powi(2)).sqrt();
+                match ignore_within {
+                    Some(ignore) if dist <= *ignore => None,
+                    _ => Some(dist <= *radius),
+                }
+            }
+        }
+    }
+
+    /// Returns true if the ground truth describes this many queries and database entries.
+    /// Every row of a matrix must have one column per entry.
+    fn has_shape(&self, num_queries: usize, num_entries: usize) -> bool {
+        match self {
+            GroundTruth::Matrix(m) => {
+                m.len() == num_queries && m.iter().all(|row| row.len() == num_entries)
+            }
+            GroundTruth::Sequence {
+                queries, database, ..
+            } => queries.len() == num_queries && database.len() == num_entries,
+            GroundTruth::Positions {
+                queries, database, ..
+            } => queries.len() == num_queries && database.len() == num_entries,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+/// A point of a precision-recall curve.
+pub struct PrPoint {
+    /// Pairs scoring at least this much are predicted to match.
+    pub threshold: f32,
+    /// Fraction of the predicted pairs which match.
+    pub precision: f32,
+    /// Fraction of the matching pairs which are predicted.
+    pub recall: f32,
+}
+
+#[derive(Debug, Clone, PartialEq, Default, Serialize, Deserialize)]
+/// Retrieval metrics of a set of queries against a database. See [`evaluate`].
+pub struct Evaluation {
+    /// Number of queries with at least one relevant entry, over which
+    /// `recall_at_k` and `mean_average_precision` are averaged.
+    pub num_queries: usize,
+    /// For each requested k, the fraction of queries with a relevant entry among their
+    /// k best scoring entries.
+    pub recall_at_k: Vec<(usize, f32)>,
+    /// Mean over queries of the average precision of their ranking of the database.
+    pub mean_average_precision: f32,
+    /// Precision and recall of predicting every (query, entry) pair scoring at least
+    /// a threshold to match, for each distinct score from highest to lowest.
+    pub pr_curve: Vec<PrPoint>,
+    /// Area under `pr_curve`, by the trapezoidal rule over recall.
+    pub pr_auc: f32,
+}
+
+/// Score every query against every database entry with `score` (higher is more similar,
+/// e.g. [`BoW::l1`]) and compute retrieval metrics, with recall@k for each k in `ks`.
+/// Returns Err if the ground truth does not have the same shape as the queries and database,
+/// or if scoring fails.
+pub fn evaluate<F: Fn(&BoW, &BoW) -> BowResult<f32>>(
+    queries: &[BoW],
+    database: &[BoW],
+    truth: &GroundTruth,
+    score: F,
+    ks: &[usize],
+) -> BowResult<Evaluation> {
+    let scores = queries
+        .iter()
+        .map(|q| database.iter().map(|d| score(q, d)).collect())
+        .collect::<BowResult<Vec<Vec<f32>>>>()?;
+    evaluate_scores(&scores, database.len(), truth, ks)
+}
+
+/// Like [`evaluate`], with precomputed `scores[q][d]` of each query `q` against each of the
+/// `num_entries` database entries. Scores are ranked by [`f32::total_cmp`], so NaN ranks
+/// above every number.
+pub fn evaluate_scores(
+    scores: &[Vec<f32>],
+    num_entries: usize,
+    truth: &GroundTruth,
+    ks: &[usize],
+) -> BowResult<Evaluation> {
+    if !truth.has_shape(scores.len(), num_entries) || scores.iter().any(|s| s.len() != num_entries)
+    {
+        return Err(BowErr::InvalidParams(
+            "ground truth must have one row per query and one column per database entry",
+        ));
+    }
+    if ks.contains(&0) {
+        return Err(BowErr::InvalidParams("recall@k needs k >= 1"));
+    }
+
+    let mut eval = Evaluation {
+        recall_at_k: ks.iter().map(|&k| (k, 0.)).collect(),
+        ..Default::default()
+    };
+    // (score, relevant) of every pair which is not ignored, for the PR curve
+    let mut pairs: Vec<(f32, bool)> = Vec::new();
+    let mut ranked: Vec<(f32, bool)> = Vec::with_capacity(num_entries);
+    for (q, row) in scores.iter().enumerate() {
+        ranked.clear();
+        ranked.extend(
+            row.iter()
+                .enumerate()
+                .filter_map(|(d, &s)| truth.relevant(q, d).map(|r| (s, r))),
+        );
+        pairs.extend_from_slice(&ranked);
+        let num_relevant = ranked.iter().filter(|r| r.1).count();
+        if num_relevant == 0 {
+            continue;
+        }
+        eval.num_queries += 1;
+        ranked.sort_by(|a, b| b.0.total_cmp(&a.0));
+
+        // Rank of the first relevant entry, counting from 1
+        let first = ranked.iter().position(|r| r.1).unwrap() + 1;
+        for (k, recall) in eval.recall_at_k.iter_mut() {
+            if first <= *k {
+                *recall += 1.;
+            }
+        }
+        let mut hits = 0;
+        let mut precision_sum = 0.;
+        for (rank, _) in ranked.iter().enumerate().filter(|(_, r)| r.1) {
+            hits += 1;
+            precision_sum += hits as f32 / (rank + 1) as f32;
+        }
+        eval.mean_average_precision += precision_sum / num_relevant as f32;
+    }
+    if eval.num_queries > 0 {
+        let n = eval.num_queries as f32;
+        eval.mean_average_precision /= n;
+        for (_, recall) in eval.recall_at_k.iter_mut() {
+            *recall /= n;
+        }
+    }
+
+    eval.pr_curve = pr_curve(pairs);
+    eval.pr_auc = eval
+        .pr_curve
+        .iter()
+        .fold((0., None), |(area, prev): (f32, Option<&PrPoint>), p| {
+            let (r0, p0) = prev.map_or((0., p.precision), |prev| (prev.recall, prev.precision));
+            (area + (p.recall - r0) * (p.precision + p0) / 2., Some(p))
+        })
+        .0;
+    Ok(eval)
+}
+
+/// One point per distinct score, from highest to lowest.
+fn pr_curve(mut pairs: Vec<(f32, bool)>) -> Vec<PrPoint> {
+    let total_relevant = pairs.iter().filter(|p| p.1).count();
+    if total_relevant == 0 {
+        return Vec::new();
+    }
+    pairs.sort_by(|a, b| b.0.total_cmp(&a.0));
+    let mut curve = Vec::new();
+    let mut hits = 0;
+    for (i, &(score, relevant)) in pairs.iter().enumerate() {
+        if relevant {
+            hits += 1;
+        }
+        if pairs.get(i + 1).map_or(true, |next| next.0 != score) {
+            curve.push(PrPoint {
+                threshold: score,
+                precision: hits as f32 / (i + 1) as f32,
+                recall: hits as f32 / total_relevant as f32,
+            });
+        }
+    }
+    curve
+}
+
+impl Evaluation {
+    /// Write the summary metrics as CSV, one `metric,value` row each.
+    pub fn write_summary_csv<W: Write>(&self, mut w: W) -> BowResult<()> {
+        writeln!(w, "metric,value")?;
+        writeln!(w, "num_queries,{}", self.num_queries)?;
+        writeln!(w, "map,{}", self.mean_average_precision)?;
+        writeln!(w, "pr_auc,{}", self.pr_auc)?;
+        for (k, recall) in self.recall_at_k.iter() {
+            writeln!(w, "recall@{},{}", k, recall)?;
+        }
+        w.flush()?;
+        Ok(())
+    }
+
+    /// Write the precision-recall curve as CSV, with a `threshold,precision,recall` header.
+    pub fn write_pr_csv<W: Write>(&self, mut w: W) -> BowResult<()> {
+        writeln!(w, "threshold,precision,recall")?;
+        for p in self.pr_curve.iter() {
+            writeln!(w, "{},{},{}", p.threshold, p.precision, p.recall)?;
+        }
+        w.flush()?;
+        Ok(())
+    }
+
+    /// Write all metrics as a JSON object with the same field names as this struct.
+    /// `recall_at_k` is written as an object from k to recall.
+    pub fn write_json<W: Write>(&self, mut w: W) -> BowResult<()> {
+        let recall_at_k: Vec<String> = self
+            .recall_at_k
+            .iter()
+            .map(|(k, r)| format!("\"{}\":{}", k, json_f32(*r)))
+            .collect();
+        let pr_curve: Vec<String> = self
+            .pr_curve
+            .iter()
+            .map(|p| {
+                format!(
+                    "{{\"threshold\":{},\"precision\":{},\"recall\":{}}}",
+                    json_f32(p.threshold),
+                    json_f32(p.precision),
+                    json_f32(p.recall)
+                )
+            })
+            .collect();
+        writeln!(
+            w,
+            "{{\"num_queries\":{},\"recall_at_k\":{{{}}},\"mean_average_precision\":{},\
+             \"pr_curve\":[{}],\"pr_auc\":{}}}",
+            self.num_queries,
+            recall_at_k.join(","),
+            json_f32(self.mean_average_precision),
+            pr_curve.join(","),
+            json_f32(self.pr_auc)
+        )?;
+        w.flush()?;
+        Ok(())
+    }
+}
+
+/// JSON has no representation of infinities and NaN.
+fn json_f32(v: f32) -> String {
+    match v.is_finite() {
+        true => v.to_string(),
+        false => "null".to_owned(),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn metrics() {
+        // Query 0 ranks its relevant entries 1st and 3rd, query 1 ranks its only one 2nd
+        let scores = vec![vec![0.9, 0.8, 0.7, 0.1], vec![0.6, 0.5, 0.2, 0.3]];
+        let truth = GroundTruth::Matrix(vec![
+            vec![true, false, true, false],
+            vec![false, true, false, false],
+        ]);
+        let eval = evaluate_scores(&scores, 4, &truth, &[1, 2]).unwrap();
+        assert_eq!(eval.num_queries, 2);
+        assert_eq!(eval.recall_at_k, [(1, 0.5), (2, 1.)]);
+        let ap = [(1. + 2. / 3.) / 2., 0.5];
+        assert!((eval.mean_average_precision - (ap[0] + ap[1]) / 2.).abs() < 1e-6);
+        assert_eq!(eval.pr_curve.len(), 8);
+        let last = eval.pr_curve.last().unwrap();
+        assert_eq!((last.precision, last.recall), (3. / 8., 1.));
+        assert!(eval.pr_auc > 0. && eval.pr_auc <= 1.);
+
+        let mut json = Vec::new();
+        eval.write_json(&mut json).unwrap();
+        let json: serde_json::Value = serde_json::from_slice(&json).unwrap();
+        assert_eq!(json["recall_at_k"]["2"], 1.);
+        let mut csv = Vec::new();
+        eval.write_pr_csv(&mut csv).unwrap();
+        assert_eq!(String::from_utf8(csv).unwrap().lines().count(), 9);
+
+        assert!(evaluate_scores(&scores, 3, &truth, &[1]).is_err());
+        // Every row of a matrix is checked, not only the first
+        let ragged = GroundTruth::Matrix(vec![vec![true, false, false], vec![true]]);
+        let square = vec![vec![0.; 3]; 2];
+        assert!(evaluate_scores(&square, 3, &ragged, &[1]).is_err());
+
+        let nan = vec![vec![0.1, f32::NAN, 0.9]];
+        let truth = GroundTruth::Matrix(vec![vec![false, false, true]]);
+        let eval = evaluate_scores(&nan, 3, &truth, &[1, 2]).unwrap();
+        assert_eq!(eval.recall_at_k, [(1, 0.), (2, 1.)]);
+    }
+
+    #[test]
+    fn sequence_truth() {
+        let bows: Vec<BoW> = (0..6)
+            .map(|i| {
+                // Neighbours in the sequence share a word
+                let mut v = vec![0.; 7];
+                v[i] = 0.5;
+                v[i + 1] = 0.5;
+                BoW(v, None)
+            })
+            .collect();
+        let truth = GroundTruth::Sequence {
+            queries: (0..6).collect(),
+            database: (0..6).collect(),
+            tolerance: 1,
+            ignore_within: Some(0),
+        };
+        assert_eq!(truth.relevant(2, 2), None);
+        assert_eq!(truth.relevant(2, 3), Some(true));
+        let eval = evaluate(&bows, &bows, &truth, |a, b| a.l1(b), &[1]).unwrap();
+        assert_eq!(eval.num_queries, 6);
+        assert_eq!(eval.recall_at_k, [(1, 1.)]);
+        assert!((eval.mean_average_precision - 1.).abs() < 1e-6);
+    }
+}
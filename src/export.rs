@@ -0,0 +1,216 @@
+use std::{collections::VecDeque, io::Write};
+
+use crate::*;
+
+#[derive(Debug, Clone, PartialEq)]
+/// Which part of the tree to export, and how much detail to include.
+pub struct ExportOptions {
+    /// Stable id of the node to export the subtree of. 0 exports the whole tree.
+    pub root: usize,
+    /// Maximum depth of exported nodes below `root`, or None for no limit.
+    pub max_depth: Option<usize>,
+    /// Maximum number of exported nodes, or None for no limit. Nodes closer to `root` are
+    /// exported first.
+    pub max_nodes: Option<usize>,
+    /// Include the hex encoded centroid descriptor of each node.
+    pub centroids: bool,
+}
+
+impl Default for ExportOptions {
+    fn default() -> Self {
+        Self {
+            root: 0,
+            max_depth: None,
+            max_nodes: Some(1000),
+            centroids: false,
+        }
+    }
+}
+
+/// A node selected for export.
+struct Exported<'a> {
+    node: Node<'a>,
+    /// Number of children which were left out by the limits.
+    hidden_children: usize,
+}
+
+/// Tree export
+impl Vocabulary {
+    /// Write the tree as a Graphviz DOT digraph. Inner nodes are boxes and words are ellipses,
+    /// labelled with their node id, level, cluster size and weight, and with their word id and
+    /// centroid where applicable. Returns Err if `options.root` is not a node.
+    pub fn export_dot<W: Write>(&self, mut w: W, options: &ExportOptions) -> BowResult<()> {
+        let nodes = self.export_nodes(options)?;
+        writeln!(w, "digraph vocabulary {{")?;
+        writeln!(w, "    node [shape=box, fontname=\"monospace\"];")?;
+        for exported in nodes.iter() {
+            let node = exported.node;
+            let mut label = match (node.id(), node.word_id()) {
+                (0, _) => "root".to_owned(),
+                (id, Some(word)) => format!("node {}\\nword {}", id, word),
+                (id, None) => format!("node {}", id),
+            };
+            label += &format!("\\nlevel {}\\nsize {}", node.depth(), node.cluster_size());
+            if let Some(weight) = node.weight() {
+                label += &format!("\\nweight {}", weight);
+            }
+            if let Some(centroid) = node.centroid().filter(|_| options.centroids) {
+                let hex = to_hex(centroid);
+                label += &format!("\\n{}\\n{}", &hex[..32], &hex[32..]);
+            }
+            if exported.hidden_children > 0 {
+                label += &format!("\\n({} children hidden)", exported.hidden_children);
+            }
+            let shape = match node.is_word() {
+                true => ", shape=ellipse",
+                false => "",
+            };
+            writeln!(w, "    n{} [label=\"{}\"{}];", node.id(), label, shape)?;
+        }
+        for exported in nodes.iter().skip(1) {
+            if let Some(parent) = exported.node.parent() {
+                writeln!(w, "    n{} -> n{};", parent.id(), exported.node.id())?;
+            }
+        }
+        writeln!(w, "}}")?;
+        w.flush()?;
+        Ok(())
+    }
+
+    /// Write the tree as JSON: an object with the exported `root` id, whether any nodes were
+    /// `truncated` by the limits, and the list of `nodes` in breadth first order. Each node has
+    /// its `id`, `parent` (null for the exported root), `level`, `word` id (null for inner nodes),
+    /// `cluster_size`, `weight` (null for the root), number of `hidden_children`, and `centroid`
+    /// as hex if requested. Returns Err if `options.root` is not a node.
+    pub fn export_json<W: Write>(&self, mut w: W, options: &ExportOptions) -> BowResult<()> {
+        let nodes = self.export_nodes(options)?;
+        let truncated = nodes.iter().any(|n| n.hidden_children > 0);
+        let json_opt = |v: Option<String>| v.unwrap_or_else(|| "null".to_owned());
+        write!(
+            w,
+            "{{\"root\":{},\"truncated\":{},\"nodes\":[",
+            options.root, truncated
+        )?;
+        for (i, exported) in nodes.iter().enumerate() {
+            let node = exported.node;
+            if i > 0 {
+                write!(w, ",")?;
+            }
+            let parent = node.parent().filter(|_| i > 0).map(|p| p.id().to_string());
+            write!(
+                w,
+                "{{\"id\":{},\"parent\":{},\"level\":{},\"word\":{},\"cluster_size\":{},\
+                 \"weight\":{},\"hidden_children\":{}",
+                node.id(),
+                json_opt(parent),
+                node.depth(),
+                json_opt(node.word_id().map(|w| w.to_string())),
+                node.cluster_size(),
+                json_opt(
+                    node.weight()
+                        .filter(|w| w.is_finite())
+                        .map(|w| w.to_string())
+                ),
+                exported.hidden_children
+            )?;
+            if options.centroids {
+                let centroid = node.centroid().map(|c| format!("\"{}\"", to_hex(c)));
+                write!(w, ",\"centroid\":{}", json_opt(centroid))?;
+            }
+            write!(w, "}}")?;
+        }
+        writeln!(w, "]}}")?;
+        w.flush()?;
+        Ok(())
+    }
+
+    /// Nodes of the subtree to export, breadth first, within the limits.
+    fn export_nodes(&self, options: &ExportOptions) -> BowResult<Vec<Exported<'_>>> {
+        let root = self.node(options.root).ok_or(BowErr::InvalidParams(
+            "export root is not a node of the tree",
+        ))?;
+        let max_depth = root
+            .depth()
+            .saturating_add(options.max_depth.unwrap_or(usize::MAX));
+        let max_nodes = options.max_nodes.unwrap_or(usize::MAX).max(1);
+
+        let mut nodes = vec![Exported {
+            node: root,
+            hidden_children: 0,
+        }];
+        let mut queue = VecDeque::from(vec![0]);
+        while let Some(i) = queue.pop_front() {
+            let node = nodes[i].node;
+            for child in node.children() {
+                if child.depth() > max_depth || nodes.len() >= max_nodes {
+                    nodes[i].hidden_children += 1;
+                } else {
+                    queue.push_back(nodes.len());
+                    nodes.push(Exported {
+                        node: child,
+                        hidden_children: 0,
+                    });
+                }
+            }
+        }
+        Ok(nodes)
+    }
+}
+
+fn to_hex(desc: &Desc) -> String {
+    desc.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use rand::{thread_rng, Rng};
+
+    #[test]
+    fn export() {
+        let features: Vec<Desc> = (0..1000).map(|_| thread_rng().gen()).collect();
+        let voc = Vocabulary::create(&features, 4, 3).unwrap();
+        let export = |options: &ExportOptions, dot: bool| -> String {
+            let mut out = Vec::new();
+            match dot {
+                true => voc.export_dot(&mut out, options).unwrap(),
+                false => voc.export_json(&mut out, options).unwrap(),
+            }
+            String::from_utf8(out).unwrap()
+        };
+
+        let all = ExportOptions {
+            max_nodes: None,
+            ..Default::default()
+        };
+        let dot = export(&all, true);
+        assert!(dot.starts_with("digraph vocabulary {"));
+        assert_eq!(dot.matches("->").count(), voc.nodes().count() - 1);
+
+        let json: serde_json::Value = serde_json::from_str(&export(&all, false)).unwrap();
+        assert_eq!(json["truncated"], false);
+        assert_eq!(json["nodes"].as_array().unwrap().len(), voc.nodes().count());
+
+        // Limits keep the output small, and record what was left out
+        let child = voc.root().unwrap().children().next().unwrap();
+        let limited = ExportOptions {
+            root: child.id(),
+            max_depth: Some(1),
+            max_nodes: Some(3),
+            centroids: true,
+        };
+        let json: serde_json::Value = serde_json::from_str(&export(&limited, false)).unwrap();
+        let nodes = json["nodes"].as_array().unwrap();
+        assert!(nodes.len() <= 3);
+        assert_eq!(nodes[0]["id"], child.id());
+        assert_eq!(nodes[0]["parent"], serde_json::Value::Null);
+        assert_eq!(nodes[0]["centroid"], to_hex(child.centroid().unwrap()));
+        assert!(export(&limited, true).contains(&to_hex(child.centroid().unwrap())[..32]));
+
+        let missing = ExportOptions {
+            root: voc.nodes().count(),
+            ..Default::default()
+        };
+        assert!(voc.export_dot(Vec::new(), &missing).is_err());
+    }
+}
@@ -0,0 +1,209 @@
+use crate::{vocab::NodeId, *};
+use std::convert::TryInto;
+
+/// Tag bit marking a child index as a word/leaf id rather than a block index.
+const LEAF: u32 = 1 << 31;
+
+/// A descriptor stored as four machine words, aligned so that a block's
+/// child descriptors share as few cache lines as possible.
+#[repr(C, align(32))]
+#[derive(Clone, Copy, PartialEq)]
+struct PackedDesc([u64; 4]);
+
+#[derive(Clone, Copy, PartialEq)]
+/// Location of a block's children within the flat arrays.
+struct FlatBlock {
+    offset: u32,
+    len: u32,
+}
+
+#[derive(Clone, PartialEq)]
+/// Compiled, read-only layout of a [`Vocabulary`] optimized for [`transform`](Self::transform).
+///
+/// The child descriptors, child indices and weights of every block are stored contiguously
+/// in three flat arrays, so descending the tree touches no heap pointers besides the arrays
+/// themselves. Produces the same output as the `Vocabulary` it was built from.
+pub struct FlatVocabulary {
+    blocks: Vec<FlatBlock>,
+    features: Vec<PackedDesc>,
+    children: Vec<u32>,
+    weights: Vec<f32>,
+    num_leaves: usize,
+    burstiness: Burstiness,
+    fingerprint: u64,
+}
+
+impl FlatVocabulary {
+    /// Compile a vocabulary into its flat representation.
+    pub fn new(voc: &Vocabulary) -> Self {
+        let num_children = voc.blocks.iter().map(|b| b.children.ids.len()).sum();
+        let mut flat = Self {
+            blocks: Vec::with_capacity(voc.blocks.len()),
+            features: Vec::with_capacity(num_children),
+            children: Vec::with_capacity(num_children),
+            weights: Vec::with_capacity(num_children),
+            num_leaves: voc.num_leaves,
+            burstiness: voc.burstiness.clone(),
+            fingerprint: voc.fingerprint(),
+        };
+
+        for block in voc.blocks.iter() {
+            let children = &block.children;
+            flat.blocks.push(FlatBlock {
+                offset: flat.children.len() as u32,
+                len: children.ids.len() as u32,
+            });
+            flat.features.extend(children.features.iter().map(pack));
+            flat.weights.extend_from_slice(&children.weights);
+            flat.children.extend(children.ids.iter().map(|id| match id {
+                NodeId::Block(b) => *b as u32,
+                NodeId::Leaf(w) => *w as u32 | LEAF,
+            }));
+        }
+
+        flat
+    }
+
+    /// Transform a vector of binary descriptors into its bag of words
+    /// representation. Equivalent to [`Vocabulary::transform`].
+    /// Returns Err if features is empty.
+    pub fn transform(&self, features: &[Desc]) -> BowResult<BoW> {
+        self.transform_inner(features, false).map(|res| res.0)
+    }
+
+    /// Transform a vector of binary descriptors into its bag of words
+    /// representation, and provide the direct index from the features to their
+    /// corresponding nodes in the Vocabulary tree.
+    /// Equivalent to [`Vocabulary::transform_with_direct_idx`].
+    pub fn transform_with_direct_idx(&self, features: &[Desc]) -> BowResult<(BoW, DirectIdx)> {
+        self.transform_inner(features, true)
+    }
+
+    /// Number of words (leaves) in the vocabulary, which is the length of each BoW vector.
+    pub fn num_words(&self) -> usize {
+        self.num_leaves
+    }
+
+    fn transform_inner(&self, features: &[Desc], di: bool) -> BowResult<(BoW, DirectIdx)> {
+        if features.is_empty() || self.blocks.is_empty() {
+            return Err(BowErr::NoFeatures);
+        }
+
+        let mut bow = BoW(vec![0.; self.num_leaves], Some(self.fingerprint));
+        let mut direct_idx: DirectIdx = Vec::with_capacity(if di { features.len() } else { 0 });
+        for feature in features {
+            let feature = pack(feature);
+            let mut path = IdPath::new();
+
+            // start at root block and traverse tree
+            let mut block = self.blocks[0];
+            loop {
+                let start = block.offset as usize;
+                let end = start + block.len as usize;
+                // Same sentinel as `Vocabulary`, whose u8 distances stop at 255: a child at 255
+                // or more only wins if it is the first one
+                let mut best_child: (u32, usize) = (u8::MAX as u32, start);
+                for (child, child_feat) in self.features[start..end].iter().enumerate() {
+                    let d = hamming(&feature, child_feat);
+                    if d < best_child.0 {
+                        best_child = (d, start + child)
+                    }
+                }
+
+                let child = self.children[best_child.1];
+                if child & LEAF == 0 {
+                    if di {
+                        path.push(child);
+                    }
+                    block = self.blocks[child as usize];
+                } else {
+                    let word_id = (child & !LEAF) as usize;
+                    if di {
+                        path.push(word_id as u32);
+                        direct_idx.push(path);
+                    }
+                    bow.0[word_id] += self.weights[best_child.1];
+                    break;
+                }
+            }
+        }
+        self.burstiness.apply(&mut bow)?;
+        bow.normalize();
+
+        Ok((bow, direct_idx))
+    }
+}
+
+impl From<&Vocabulary> for FlatVocabulary {
+    fn from(voc: &Vocabulary) -> Self {
+        Self::new(voc)
+    }
+}
+
+impl std::fmt::Debug for FlatVocabulary {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("FlatVocabulary")
+            .field("Blocks", &self.blocks.len())
+            .field("Nodes", &self.children.len())
+            .field("Word/Leaf Nodes", &self.num_leaves)
+            .finish()
+    }
+}
+
+#[inline]
+fn pack(desc: &Desc) -> PackedDesc {
+    let mut words = [0_u64; 4];
+    for (w, chunk) in words.iter_mut().zip(desc.chunks_exact(8)) {
+        *w = u64::from_ne_bytes(chunk.try_into().unwrap());
+    }
+    PackedDesc(words)
+}
+
+#[inline]
+/// Hamming distance between two packed descriptors.
+fn hamming(x: &PackedDesc, y: &PackedDesc) -> u32 {
+    (x.0[0] ^ y.0[0]).count_ones()
+        + (x.0[1] ^ y.0[1]).count_ones()
+        + (x.0[2] ^ y.0[2]).count_ones()
+        + (x.0[3] ^ y.0[3]).count_ones()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use rand::{thread_rng, Rng};
+
+    #[test]
+    fn flat_transform_matches_vocabulary() {
+        let mut rng = thread_rng();
+        let features: Vec<Desc> = (0..2000).map(|_| rng.gen()).collect();
+        let voc = Vocabulary::create(&features, 6, 3).unwrap();
+        let flat = FlatVocabulary::new(&voc);
+
+        let query: Vec<Desc> = (0..300).map(|_| rng.gen()).collect();
+        assert_eq!(
+            voc.transform(&query).unwrap(),
+            flat.transform(&query).unwrap()
+        );
+        assert_eq!(
+            voc.transform_with_direct_idx(&query).unwrap(),
+            flat.transform_with_direct_idx(&query).unwrap()
+        );
+
+        // Centroids with a single zero bit are at distance 255 from an all zero query
+        let far: Vec<Desc> = (0..8)
+            .flat_map(|i| {
+                let mut desc = [u8::MAX; 32];
+                desc[i * 4] = !1;
+                vec![desc; 10]
+            })
+            .collect();
+        let voc = Vocabulary::create(&far, 8, 1).unwrap();
+        let flat = FlatVocabulary::new(&voc);
+        let query = [[0; 32], [u8::MAX; 32], far[30]];
+        assert_eq!(
+            voc.transform_with_direct_idx(&query).unwrap(),
+            flat.transform_with_direct_idx(&query).unwrap()
+        );
+    }
+}
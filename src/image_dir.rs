@@ -0,0 +1,188 @@
+//! Listing the images in a directory, and extracting their features one image at a time,
+//! optionally on several threads. Used by both feature extractors.
+use crate::{BowResult, Desc};
+use std::{
+    path::{Path, PathBuf},
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+/// Default file extensions of [`DirOptions`], compared case-insensitively. Both feature
+/// extractors can decode all of them.
+pub const IMAGE_EXTENSIONS: &[&str] = &[
+    "bmp", "jpeg", "jpg", "pbm", "pgm", "png", "ppm", "tif", "tiff", "webp",
+];
+
+#[derive(Debug, Clone)]
+/// Which files of a directory are treated as images, and how they are processed.
+pub struct DirOptions {
+    /// Also visit subdirectories. Symbolic links to directories are not followed.
+    pub recursive: bool,
+    /// File extensions of images, compared case-insensitively.
+    pub extensions: Vec<String>,
+    /// Also visit files and directories whose name starts with a dot.
+    pub include_hidden: bool,
+    /// Extract features on all available cores. Results are in the same order either way.
+    pub parallel: bool,
+}
+
+impl Default for DirOptions {
+    fn default() -> Self {
+        Self {
+            recursive: false,
+            extensions: IMAGE_EXTENSIONS.iter().map(|e| e.to_string()).collect(),
+            include_hidden: false,
+            parallel: false,
+        }
+    }
+}
+
+/// Paths of the images in a directory, in sorted order.
+pub fn image_paths<P: AsRef<Path>>(dir: P, options: &DirOptions) -> BowResult<Vec<PathBuf>> {
+    let mut paths = Vec::new();
+    visit(dir.as_ref(), options, &mut paths)?;
+    paths.sort();
+    Ok(paths)
+}
+
+fn visit(dir: &Path, options: &DirOptions, paths: &mut Vec<PathBuf>) -> BowResult<()> {
+    for entry in dir.read_dir()? {
+        let entry = entry?;
+        let path = entry.path();
+        let hidden = entry.file_name().to_string_lossy().starts_with('.');
+        if hidden && !options.include_hidden {
+            continue;
+        }
+        if entry.file_type()?.is_dir() {
+            if options.recursive {
+                visit(&path, options, paths)?;
+            }
+        } else if path.is_file() && has_extension(&path, &options.extensions) {
+            paths.push(path);
+        }
+    }
+    Ok(())
+}
+
+fn has_extension(path: &Path, extensions: &[String]) -> bool {
+    match path.extension().and_then(|e| e.to_str()) {
+        Some(ext) => extensions.iter().any(|e| e.eq_ignore_ascii_case(ext)),
+        None => false,
+    }
+}
+
+/// Run `extract` on each path, on one thread per core if `parallel`.
+/// Each thread gets its own copy of `config`. Returns the first error in path order.
+#[cfg_attr(
+    not(any(feature = "opencv", feature = "native-features")),
+    allow(dead_code)
+)]
+pub(crate) fn extract_all<C, F>(
+    paths: Vec<PathBuf>,
+    parallel: bool,
+    config: &C,
+    extract: F,
+) -> BowResult<Vec<(PathBuf, Vec<Desc>)>>
+where
+    C: Clone + Send,
+    F: Fn(&Path, &C) -> BowResult<Vec<Desc>> + Sync,
+{
+    let threads = match parallel {
+        true => std::thread::available_parallelism().map_or(1, |n| n.get()),
+        false => 1,
+    };
+    if threads == 1 {
+        return paths
+            .into_iter()
+            .map(|path| {
+                let descs = extract(&path, config)?;
+                Ok((path, descs))
+            })
+            .collect();
+    }
+
+    // Threads take the next unprocessed path until none are left
+    let next = AtomicUsize::new(0);
+    let mut results: Vec<(usize, BowResult<Vec<Desc>>)> = std::thread::scope(|s| {
+        let workers: Vec<_> = (0..threads.min(paths.len()))
+            .map(|_| {
+                let config = config.clone();
+                let (next, paths, extract) = (&next, &paths, &extract);
+                s.spawn(move || {
+                    let mut done = Vec::new();
+                    loop {
+                        let i = next.fetch_add(1, Ordering::Relaxed);
+                        match paths.get(i) {
+                            Some(path) => done.push((i, extract(path, &config))),
+                            None => return done,
+                        }
+                    }
+                })
+            })
+            .collect();
+        workers
+            .into_iter()
+            .flat_map(|w| w.join().expect("feature extraction thread panicked"))
+            .collect()
+    });
+    results.sort_by_key(|r| r.0);
+    paths
+        .into_iter()
+        .zip(results)
+        .map(|(path, (_, descs))| Ok((path, descs?)))
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::fs;
+
+    #[test]
+    fn list_and_extract() {
+        let dir = std::env::temp_dir().join(format!("abow_image_dir_test_{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(dir.join("sub")).unwrap();
+        fs::create_dir_all(dir.join(".hidden")).unwrap();
+        for file in &[
+            "b.JPG",
+            "a.png",
+            "notes.txt",
+            ".c.jpg",
+            "sub/d.jpg",
+            ".hidden/e.jpg",
+        ] {
+            fs::write(dir.join(file), file.as_bytes()).unwrap();
+        }
+
+        let names = |options: &DirOptions| -> Vec<String> {
+            image_paths(&dir, options)
+                .unwrap()
+                .iter()
+                .map(|p| p.strip_prefix(&dir).unwrap().to_string_lossy().into_owned())
+                .collect()
+        };
+        let mut options = DirOptions::default();
+        assert_eq!(names(&options), ["a.png", "b.JPG"]);
+        options.recursive = true;
+        assert_eq!(names(&options), ["a.png", "b.JPG", "sub/d.jpg"]);
+        options.include_hidden = true;
+        assert_eq!(
+            names(&options),
+            [".c.jpg", ".hidden/e.jpg", "a.png", "b.JPG", "sub/d.jpg"]
+        );
+
+        // One fake descriptor per byte of the file, in the same order with or without threads
+        let paths = image_paths(&dir, &options).unwrap();
+        let fake = |path: &Path, fill: &u8| -> BowResult<Vec<Desc>> {
+            Ok(fs::read(path)?.iter().map(|_| [*fill; 32]).collect())
+        };
+        let serial = extract_all(paths.clone(), false, &7, fake).unwrap();
+        assert_eq!(serial.len(), 5);
+        assert_eq!(serial[2], (dir.join("a.png"), vec![[7; 32]; 5]));
+        assert_eq!(extract_all(paths.clone(), true, &7, fake).unwrap(), serial);
+
+        fs::remove_file(dir.join("a.png")).unwrap();
+        assert!(extract_all(paths, true, &7, fake).is_err());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
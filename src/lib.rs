@@ -5,23 +5,128 @@ use thiserror::Error;
 /// Implementation of a visual bag-of-words vocabulary,
 /// which provides the main functionality of this create.
 pub mod vocab;
-pub use vocab::Vocabulary;
+pub use vocab::{
+    ClusterInitMethod, SoftAssignment, TrainingParams, Vocabulary, VOCABULARY_VERSION,
+};
+
+/// Read-only access to the nodes of the vocabulary tree.
+pub mod tree;
+pub use tree::Node;
+
+/// Statistics of a vocabulary's structure.
+pub mod stats;
+pub use stats::{Distribution, VocabularyStats};
+
+/// Export of the vocabulary tree to Graphviz DOT and JSON.
+pub mod export;
+pub use export::ExportOptions;
+
+/// Searchable, persistent database of BoW vectors.
+pub mod database;
+pub use database::{Database, EntryId, Metadata, QueryFilter, QueryOptions, QueryResult};
+
+/// Retrieval evaluation with precision-recall curves, recall@k and mAP.
+pub mod eval;
+pub use eval::{evaluate, Evaluation, GroundTruth};
+
+/// Search over vocabulary training parameters for the best retrieval quality.
+pub mod sweep;
+pub use sweep::{sweep, Criterion, Sweep, SweepGrid, Weighting};
+
+/// Compiled, read-only vocabulary layout for low-latency transforms.
+pub mod flat;
+pub use flat::FlatVocabulary;
+
+/// Suppression of bursty visual words.
+pub mod burstiness;
+pub use burstiness::Burstiness;
+
+/// Residual signatures of descriptors for re-ranking word matches.
+pub mod embedding;
+pub use embedding::{EmbeddedBoW, Signature, WordSignature};
+
+/// Binary and text files of descriptors grouped per image.
+pub mod descriptor_file;
+pub use descriptor_file::ImageDescriptors;
+
+/// Listing and processing the images of a directory.
+pub mod image_dir;
+pub use image_dir::{image_paths, DirOptions};
 
 /// Utilities for extracting feature descriptors using opencv.
 pub mod opencv_utils;
 #[cfg(feature = "opencv")]
 pub use opencv_utils::*;
 
+/// Extracting features from videos and image sequences using opencv.
+pub mod video;
+#[cfg(feature = "opencv")]
+pub use video::{Frame, FrameBoW, FrameSampling};
+
+/// Mosaics of the image patches assigned to each visual word, using opencv.
+pub mod atlas;
+#[cfg(feature = "opencv")]
+pub use atlas::{AtlasOptions, PatchAtlas};
+
+/// Pure-Rust ORB feature extraction, for builds without opencv.
+pub mod native_features;
+#[cfg(all(feature = "native-features", not(feature = "opencv")))]
+pub use native_features::*;
+
 /// Supported descriptor type is 256-bit array.
 pub type Desc = [u8; 32];
 
+/// An image keypoint, at which a descriptor was computed.
+#[derive(Debug, Clone, Copy, PartialEq, Default, Serialize, Deserialize)]
+pub struct KeyPoint {
+    /// Horizontal position in pixels.
+    pub x: f32,
+    /// Vertical position in pixels.
+    pub y: f32,
+    /// Diameter of the keypoint neighborhood in pixels.
+    pub size: f32,
+    /// Orientation in degrees, in [0, 360).
+    pub angle: f32,
+    /// Detector response, i.e. corner strength.
+    pub response: f32,
+    /// Pyramid level the keypoint was detected at.
+    pub octave: i32,
+}
+
+#[cfg(feature = "bincode")]
+const BOW_MAGIC: &[u8; 8] = b"ABOWBOW\0";
+
+/// Version of the BoW file format written by [`BoW::save`].
+pub const BOW_VERSION: u32 = 1;
+
 /// Bag-of-Words representation of an image or descriptor set.
 ///
 /// Index: word/leaf id in the vocabulary.
 ///
 /// Value: total weight of that word in provided features.
+///
+/// The second field is the [fingerprint](Vocabulary::fingerprint) of the vocabulary which
+/// produced the vector, or None for vectors built by hand. Vectors with different fingerprints
+/// cannot be compared.
 #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
-pub struct BoW(pub Vec<f32>);
+pub struct BoW(pub Vec<f32>, pub Option<u64>);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+/// Similarity between two l1 normalized BoW vectors. Higher is more similar,
+/// and identical vectors score 1 except with `DotProduct`. See [`BoW::score`].
+pub enum Scoring {
+    /// `1 - 0.5 * |a - b|_1`, see [`BoW::l1`].
+    #[default]
+    L1,
+    /// `1 - |a' - b'|_2 / sqrt(2)`, where `a'` and `b'` are l2 normalized.
+    L2,
+    /// `sum(2 a_i b_i / (a_i + b_i))`, which is `1 - 0.5 * chi^2(a, b)`.
+    ChiSquare,
+    /// Bhattacharyya coefficient `sum(sqrt(a_i b_i))`.
+    Bhattacharyya,
+    /// `sum(a_i b_i)`.
+    DotProduct,
+}
 
 /// A map from features to their corresponding nodes in the Vocabulary tree.
 /// Each feature maps to several nodes, up to one for each level of the tree.
@@ -32,15 +137,132 @@ pub struct BoW(pub Vec<f32>);
 /// at level `j` in the Vocabulary tree.
 pub type DirectIdx = Vec<IdPath>;
 
-/// The path from the root to the leaf for a given feature.
-// Only 5 entries are stack allocated, so level > 5 would have poor performance.
-pub type IdPath = SmallVec<[usize; 5]>;
+/// The path from the root to the leaf for a given feature: the ids of the blocks
+/// below the root, followed by the word/leaf id.
+// Paths of up to 8 levels are stack allocated. They are only built when a direct index is requested.
+pub type IdPath = SmallVec<[u32; 8]>;
+
+/// A direct index in which each feature's path is paired with the feature's keypoint,
+/// so that the nodes matching a feature can be traced back to its image location.
+pub type KeyPointIdx = Vec<(KeyPoint, IdPath)>;
 
 impl BoW {
     /// Compute L1 norm between two BoW. (Used in Galvez (Eq 2)).
-    pub fn l1(&self, other: &Self) -> f32 {
+    /// Returns Err if the vectors are not comparable, see [`Self::check_compatible`].
+    pub fn l1(&self, other: &Self) -> BowResult<f32> {
+        self.score(other, Scoring::L1)
+    }
+
+    /// Similarity to another BoW with the given scoring function.
+    /// Returns Err if the vectors are not comparable, see [`Self::check_compatible`].
+    pub fn score(&self, other: &Self, scoring: Scoring) -> BowResult<f32> {
+        self.check_compatible(other)?;
         let values = self.0.iter().zip(&other.0);
-        1. - 0.5 * (values.fold(0., |a, (b, c)| a + (b - c).abs()))
+        Ok(match scoring {
+            Scoring::L1 => 1. - 0.5 * (values.fold(0., |a, (b, c)| a + (b - c).abs())),
+            Scoring::L2 => {
+                let norm = |v: &Self| v.0.iter().map(|x| x * x).sum::<f32>().sqrt().max(1e-12);
+                let (na, nb) = (norm(self), norm(other));
+                let dist = values.map(|(a, b)| (a / na - b / nb).powi(2)).sum::<f32>();
+                1. - dist.sqrt() / std::f32::consts::SQRT_2
+            }
+            Scoring::ChiSquare => values
+                .filter(|(a, b)| **a + **b > 0.)
+                .map(|(a, b)| 2. * a * b / (a + b))
+                .sum(),
+            Scoring::Bhattacharyya => values.map(|(a, b)| (a * b).sqrt()).sum(),
+            Scoring::DotProduct => values.map(|(a, b)| a * b).sum(),
+        })
+    }
+
+    /// Returns Err if the vectors have different lengths, or were produced by
+    /// vocabularies with different fingerprints.
+    pub fn check_compatible(&self, other: &Self) -> BowResult<()> {
+        if self.0.len() != other.0.len() {
+            return Err(BowErr::DimensionMismatch {
+                expected: self.0.len(),
+                found: other.0.len(),
+            });
+        }
+        match (self.1, other.1) {
+            (Some(expected), Some(found)) if expected != found => {
+                Err(BowErr::VocabularyMismatch { expected, found })
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Load a BoW vector from a file. Files written before the format was versioned
+    /// (abow 0.4.2 and earlier) are loaded without a fingerprint.
+    /// Returns Err if the file was saved in another format version, see [`BOW_VERSION`].
+    #[cfg(feature = "bincode")]
+    pub fn load<P: AsRef<std::path::Path>>(file: P) -> BowResult<Self> {
+        let buffer = std::fs::read(file)?;
+        match read_versioned(&buffer, BOW_MAGIC, BOW_VERSION, "BoW") {
+            Ok(data) => Ok(bincode::deserialize(data)?),
+            // Unversioned files hold only the weights, as a u64 length and f32 values
+            Err(BowErr::UnsupportedVersion { version: 0, .. })
+                if bincode::deserialize::<u64>(&buffer)
+                    .is_ok_and(|n| n.checked_mul(4) == Some(buffer.len() as u64 - 8)) =>
+            {
+                Ok(BoW(bincode::deserialize(&buffer)?, None))
+            }
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Save BoW vector to a file
+    #[cfg(feature = "bincode")]
+    pub fn save<P: AsRef<std::path::Path>>(&self, file: P) -> BowResult<()> {
+        save_versioned(file, BOW_MAGIC, BOW_VERSION, self)
+    }
+
+    /// Scale the BoW to unit L1 norm. Does nothing if all weights are zero.
+    pub(crate) fn normalize(&mut self) {
+        let sum: f32 = self.0.iter().sum();
+        if sum > 0. {
+            let inv_sum = 1. / sum;
+            for w in self.0.iter_mut() {
+                *w *= inv_sum;
+            }
+        }
+    }
+}
+
+/// Write a file of an 8 byte magic, a u32 little-endian format version and the bincode of `value`.
+#[cfg(feature = "bincode")]
+pub(crate) fn save_versioned<P: AsRef<std::path::Path>, T: Serialize>(
+    file: P,
+    magic: &[u8; 8],
+    version: u32,
+    value: &T,
+) -> BowResult<()> {
+    let mut buffer = magic.to_vec();
+    buffer.extend_from_slice(&version.to_le_bytes());
+    bincode::serialize_into(&mut buffer, value)?;
+    std::fs::write(file, buffer)?;
+    Ok(())
+}
+
+/// The data of a file written by [`save_versioned`], after checking its magic and version.
+/// Files without the magic were written before formats were versioned and are version 0.
+#[cfg(feature = "bincode")]
+pub(crate) fn read_versioned<'a>(
+    buffer: &'a [u8],
+    magic: &[u8; 8],
+    version: u32,
+    kind: &'static str,
+) -> BowResult<&'a [u8]> {
+    let found = match buffer.strip_prefix(&magic[..]) {
+        Some(rest) if rest.len() >= 4 => u32::from_le_bytes([rest[0], rest[1], rest[2], rest[3]]),
+        _ => 0,
+    };
+    match found == version {
+        true => Ok(&buffer[magic.len() + 4..]),
+        false => Err(BowErr::UnsupportedVersion {
+            kind,
+            version: found,
+        }),
     }
 }
 
@@ -49,8 +271,14 @@ type BowResult<T> = std::result::Result<T, BowErr>;
 pub enum BowErr {
     #[error("No Features Provided")]
     NoFeatures,
+    #[error("Invalid Parameters: {0}")]
+    InvalidParams(&'static str),
     #[error("Io Error")]
     Io(#[from] std::io::Error),
+    #[error("Unsupported {kind} File Version {version}")]
+    UnsupportedVersion { kind: &'static str, version: u32 },
+    #[error("Invalid Descriptor File: {0}")]
+    DescriptorFormat(String),
     #[cfg(feature = "bincode")]
     #[error("Vocabulary Serialization Error")]
     Bincode(#[from] bincode::Error),
@@ -58,75 +286,87 @@ pub enum BowErr {
     #[error("Opencv Error")]
     OpenCvInternal(#[from] opencv::Error),
     #[cfg(feature = "opencv")]
-    #[error("Opencv Descriptor decode error")]
-    OpenCvDecode,
+    #[error("Unexpected Opencv Descriptor Matrix: {0}")]
+    OpenCvDescriptorMatrix(String),
+    #[error("Could Not Decode Image: {0:?}")]
+    UnreadableImage(std::path::PathBuf),
+    #[error("Image Has No Pixels")]
+    EmptyImage,
+    #[error("Path Is Not Valid UTF-8: {0:?}")]
+    NonUtf8Path(std::path::PathBuf),
+    #[error("Vocabulary Mismatch: Expected Fingerprint {expected:016x}, Found {found:016x}")]
+    VocabularyMismatch { expected: u64, found: u64 },
+    #[error("Dimension Mismatch: Expected {expected} Weights, Found {found}")]
+    DimensionMismatch { expected: usize, found: usize },
+    #[error("No Database Entry With Id {0}")]
+    UnknownEntry(usize),
+    #[error("Invalid Database File: {0}")]
+    DatabaseFormat(String),
+    #[cfg(feature = "native-features")]
+    #[error("Image Error")]
+    Image(#[from] image::ImageError),
 }
 
 #[cfg(test)]
-#[cfg(feature = "opencv")]
+#[cfg(any(feature = "bincode", feature = "opencv"))]
 mod test {
     use super::*;
-    use std::path::{Path, PathBuf};
+
+    #[cfg(feature = "bincode")]
     #[test]
-    /// Performs a somewhat ad-hoc parameter search for the highest recall as a function
-    /// of `l` and `k`. l=4 and k=10 is apparently best.
+    fn bow_file() {
+        let path = std::env::temp_dir().join(format!("abow_test_{}.bow", std::process::id()));
+        let bow = BoW(vec![0.25, 0., 0.75], Some(7));
+        bow.save(&path).unwrap();
+        assert_eq!(BoW::load(&path).unwrap(), bow);
+
+        // Files from before the fingerprint was added hold only the weights
+        std::fs::write(&path, bincode::serialize(&bow.0).unwrap()).unwrap();
+        assert_eq!(BoW::load(&path).unwrap(), BoW(bow.0.clone(), None));
+        std::fs::write(&path, [1, 2, 3]).unwrap();
+        assert!(matches!(
+            BoW::load(&path),
+            Err(BowErr::UnsupportedVersion { version: 0, .. })
+        ));
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[cfg(feature = "opencv")]
+    #[test]
+    /// Sweeps `l` and `k` for the best retrieval on the test sequence.
+    /// Images are relevant to each other if their frame numbers differ by at most 6.
     fn test_recall() {
-        // Load existing vocabulary
-        let features = all_kps_from_dir("data/train").unwrap();
-        println!("Detected {} ORB features.", features.len());
-
-        for &k in &[6_usize, 8_usize, 10_usize] {
-            for &l in &[3_usize, 4_usize, 5_usize] {
-                for _ in 0..2 {
-                    // Create vocabulary from features
-                    let voc = Vocabulary::create(&features, k, l);
-                    println!("Vocabulary: {:#?}", voc);
-
-                    // Create BoW vectors from the test data. Save file name for demonstration.
-                    let mut bows: Vec<(PathBuf, BoW)> = Vec::new();
-                    for entry in Path::new("data/test").read_dir().expect("Error").flatten() {
-                        let new_feat = load_img_get_kps(&entry.path()).unwrap();
-                        bows.push((entry.path(), voc.transform(&new_feat).unwrap()));
-                    }
-
-                    // sort the files just for nicer output
-                    let num = |s: &str| -> usize {
-                        let s = s.strip_suffix(".jpg").unwrap();
-                        s.parse().unwrap()
-                    };
-                    bows.sort_by(|a, b| {
-                        num(a.0.file_name().unwrap().to_str().unwrap())
-                            .partial_cmp(&num(b.0.file_name().unwrap().to_str().unwrap()))
-                            .unwrap()
-                    });
-
-                    let mut cost = 0;
-
-                    // Compare a few images to the the whole collection using L1 norm
-                    for (f1, bow1) in bows.iter().skip(12).take(158) {
-                        let mut scores: Vec<(f32, usize, i32)> = Vec::new();
-                        let reference = num(f1.file_name().unwrap().to_str().unwrap());
-
-                        for (f2, bow2) in bows.iter() {
-                            let d = bow1.l1(bow2);
-                            let matched = num(f2.file_name().unwrap().to_str().unwrap());
-                            let cost = i32::abs(matched as i32 - reference as i32);
-                            scores.push((d, matched, cost));
-                        }
-
-                        // Print out the top 5 matches for each image
-                        let base_cost = 36; // 0 + 1 + 1 + 2 + 2 + 3 + 3 + 4 + 4 + 5 + 5 + 6
-
-                        scores.sort_by(|a, b| b.0.partial_cmp(&a.0).unwrap());
-                        for m in scores[..12].iter() {
-                            cost += m.2;
-                        }
-                        cost -= base_cost;
-                    }
-
-                    println!("k: {}, l: {}. Total Cost: {}", k, l, cost);
-                }
-            }
+        let training =
+            features_from_dir("data/train", &OrbConfig::default(), &DirOptions::default()).unwrap();
+        let training: Vec<Vec<Desc>> = training.into_iter().map(|i| i.1).collect();
+
+        let paths = image_paths("data/test", &DirOptions::default()).unwrap();
+        let frames: Vec<i64> = paths
+            .iter()
+            .map(|p| p.file_stem().unwrap().to_str().unwrap().parse().unwrap())
+            .collect();
+        let truth = GroundTruth::Sequence {
+            queries: frames.clone(),
+            database: frames,
+            tolerance: 6,
+            ignore_within: Some(0),
+        };
+        let test: Vec<Vec<Desc>> = paths
+            .iter()
+            .map(|p| load_img_get_kps(p, &OrbConfig::default()).unwrap())
+            .collect();
+
+        let result = sweep(&training, &test, &test, &truth, &SweepGrid::default()).unwrap();
+        for r in result.results.iter() {
+            println!(
+                "k: {}, l: {}. mAP: {:.3}, PR AUC: {:.3}, recall@k: {:?}",
+                r.config.training.k,
+                r.config.training.l,
+                r.evaluation.mean_average_precision,
+                r.evaluation.pr_auc,
+                r.evaluation.recall_at_k
+            );
         }
+        assert!(result.best().evaluation.recall_at_k[1].1 >= 0.5);
     }
 }
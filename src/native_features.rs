@@ -0,0 +1,522 @@
+#![cfg(feature = "native-features")]
+//! ORB keypoints and descriptors (Rublee et al., "ORB: an efficient alternative to SIFT or SURF")
+//! computed in pure Rust: FAST-9 corners on an image pyramid, ranked by Harris score, oriented
+//! by intensity centroid and described by 256-bit rotated BRIEF tests.
+//!
+//! The functions mirror those in `opencv_utils`. The BRIEF test pattern is generated
+//! deterministically, but is not OpenCV's learned pattern, so descriptors are not
+//! interchangeable with OpenCV ORB descriptors. Vocabularies need to be trained and used
+//! with the same extractor.
+use crate::{image_dir::extract_all, image_paths, BowErr, BowResult, Desc, DirOptions, KeyPoint};
+use image::{imageops, GrayImage};
+use std::path::{Path, PathBuf};
+
+/// Radius of the Bresenham circle used by the FAST segment test.
+const FAST_RADIUS: u32 = 3;
+/// Number of contiguous circle pixels which must all be brighter or darker.
+const FAST_ARC: usize = 9;
+/// Offsets of the 16 pixels on the FAST circle, clockwise from the top.
+const CIRCLE: [(i32, i32); 16] = [
+    (0, -3),
+    (1, -3),
+    (2, -2),
+    (3, -1),
+    (3, 0),
+    (3, 1),
+    (2, 2),
+    (1, 3),
+    (0, 3),
+    (-1, 3),
+    (-2, 2),
+    (-3, 1),
+    (-3, 0),
+    (-3, -1),
+    (-2, -2),
+    (-1, -3),
+];
+/// Half size of the window over which the Harris score is computed.
+const HARRIS_RADIUS: i32 = 3;
+const HARRIS_K: f32 = 0.04;
+/// Half size of the square containing all BRIEF test points.
+const PATTERN_RADIUS: f32 = 13.;
+/// Seed of the BRIEF test pattern. Changing it changes every descriptor.
+const PATTERN_SEED: u64 = 0x0ab0_0ab0_0ab0_0ab0;
+/// Standard deviation and half size of the Gaussian smoothing applied before the BRIEF tests.
+const SMOOTHING_SIGMA: f32 = 2.;
+const SMOOTHING_RADIUS: i32 = 3;
+
+#[derive(Debug, Clone)]
+/// Parameters of the ORB detector. The defaults match OpenCV's `ORB::create()`.
+pub struct OrbConfig {
+    /// Maximum number of features to retain.
+    pub n_features: i32,
+    /// Pyramid decimation ratio, greater than 1.
+    pub scale_factor: f32,
+    /// Number of pyramid levels.
+    pub n_levels: i32,
+    /// Size of the border where features are not detected. Should roughly match `patch_size`.
+    pub edge_threshold: i32,
+    /// Size of the patch used to compute the keypoint orientation.
+    pub patch_size: i32,
+    /// Threshold of the FAST corner detector.
+    pub fast_threshold: i32,
+    /// Optional mask of the same size as the image.
+    /// Features are only detected where the mask is non-zero.
+    pub mask: Option<GrayImage>,
+}
+
+impl Default for OrbConfig {
+    fn default() -> Self {
+        Self {
+            n_features: 500,
+            scale_factor: 1.2,
+            n_levels: 8,
+            edge_threshold: 31,
+            patch_size: 31,
+            fast_threshold: 20,
+            mask: None,
+        }
+    }
+}
+
+/// Extract orb keypoints and their descriptors from a grayscale image.
+pub fn orb_from_image(
+    img: &GrayImage,
+    config: &OrbConfig,
+) -> BowResult<(Vec<KeyPoint>, Vec<Desc>)> {
+    if config.n_features < 1
+        || config.n_levels < 1
+        || config.scale_factor.is_nan()
+        || config.scale_factor <= 1.
+        || config.patch_size < 2
+        || config.fast_threshold < 1
+    {
+        return Err(BowErr::InvalidParams(
+            "orb needs n_features, n_levels >= 1, scale_factor > 1, patch_size >= 2 and fast_threshold >= 1",
+        ));
+    }
+    if img.width() == 0 || img.height() == 0 {
+        return Err(BowErr::EmptyImage);
+    }
+    if let Some(mask) = &config.mask {
+        if mask.dimensions() != img.dimensions() {
+            return Err(BowErr::InvalidParams(
+                "mask must have the same size as the image",
+            ));
+        }
+    }
+
+    let pattern = brief_pattern();
+    let border = config.edge_threshold.max(FAST_RADIUS as i32 + 1) as u32;
+    let mut keypoints = Vec::new();
+    let mut descriptors = Vec::new();
+    for (level, n_level) in features_per_level(config).into_iter().enumerate() {
+        let scale = config.scale_factor.powi(level as i32);
+        let (w, h) = (
+            (img.width() as f32 / scale).round() as u32,
+            (img.height() as f32 / scale).round() as u32,
+        );
+        if w <= 2 * border || h <= 2 * border {
+            break;
+        }
+        let level_img = if level == 0 {
+            img.clone()
+        } else {
+            imageops::thumbnail(img, w, h)
+        };
+
+        // Detect corners, and retain the best by Harris score
+        let mut corners: Vec<(u32, u32, f32)> = fast_corners(&level_img, config, border)
+            .into_iter()
+            .filter(|&(x, y)| match &config.mask {
+                Some(mask) => {
+                    let mx = ((x as f32 * scale) as u32).min(mask.width() - 1);
+                    let my = ((y as f32 * scale) as u32).min(mask.height() - 1);
+                    mask.get_pixel(mx, my)[0] > 0
+                }
+                None => true,
+            })
+            .map(|(x, y)| (x, y, harris_score(&level_img, x, y)))
+            .collect();
+        corners.sort_by(|a, b| b.2.partial_cmp(&a.2).unwrap());
+        corners.truncate(n_level);
+
+        // Orient and describe them
+        let smoothed = gaussian_blur(&level_img);
+        for (x, y, response) in corners {
+            let angle = intensity_centroid_angle(&level_img, x, y, config.patch_size / 2);
+            descriptors.push(rotated_brief(&smoothed, x, y, angle, &pattern));
+            keypoints.push(KeyPoint {
+                x: x as f32 * scale,
+                y: y as f32 * scale,
+                size: config.patch_size as f32 * scale,
+                angle: angle.to_degrees().rem_euclid(360.),
+                response,
+                octave: level as i32,
+            });
+        }
+    }
+
+    Ok((keypoints, descriptors))
+}
+
+/// Load an image and extract orb keypoint descriptors.
+pub fn load_img_get_kps<P: AsRef<Path>>(path: P, config: &OrbConfig) -> BowResult<Vec<Desc>> {
+    load_img_get_features(path, config).map(|res| res.1)
+}
+
+/// Load an image and extract orb keypoints, and their descriptors in the same order.
+pub fn load_img_get_features<P: AsRef<Path>>(
+    path: P,
+    config: &OrbConfig,
+) -> BowResult<(Vec<KeyPoint>, Vec<Desc>)> {
+    let path = path.as_ref();
+    let img = image::open(path)
+        .map_err(|e| match e {
+            image::ImageError::IoError(e) => BowErr::Io(e),
+            image::ImageError::Decoding(_) | image::ImageError::Unsupported(_) => {
+                BowErr::UnreadableImage(path.to_owned())
+            }
+            e => BowErr::Image(e),
+        })?
+        .to_luma8();
+    orb_from_image(&img, config)
+}
+
+/// Extract orb keypoint descriptors from all images in a directory, grouped per image.
+/// Images are listed by [`image_paths`](crate::image_paths), and returned in the same order.
+pub fn features_from_dir<P: AsRef<Path>>(
+    path: P,
+    config: &OrbConfig,
+    options: &DirOptions,
+) -> BowResult<Vec<(PathBuf, Vec<Desc>)>> {
+    let paths = image_paths(path, options)?;
+    extract_all(paths, options.parallel, config, |path, config| {
+        load_img_get_kps(path, config)
+    })
+}
+
+/// Extract orb keypoint descriptors from all images in directory.
+/// See [`features_from_dir`] to keep track of the image of each descriptor.
+pub fn all_kps_from_dir<P: AsRef<Path>>(path: P, config: &OrbConfig) -> BowResult<Vec<Desc>> {
+    let images = features_from_dir(path, config, &DirOptions::default())?;
+    Ok(images.into_iter().flat_map(|i| i.1).collect())
+}
+
+/// Distribute the features over the pyramid levels in proportion to their area, like OpenCV.
+fn features_per_level(config: &OrbConfig) -> Vec<usize> {
+    let factor = 1. / config.scale_factor;
+    let n_features = config.n_features as usize;
+    let mut desired = n_features as f32 * (1. - factor) / (1. - factor.powi(config.n_levels));
+    let mut counts = Vec::with_capacity(config.n_levels as usize);
+    for _ in 1..config.n_levels {
+        counts.push(desired.round() as usize);
+        desired *= factor;
+    }
+    counts.push(n_features.saturating_sub(counts.iter().sum()));
+    counts
+}
+
+/// FAST-9 corners further than `border` from the edges of the image, after 3x3 non-maximum
+/// suppression of the sum of absolute differences to the circle pixels.
+fn fast_corners(img: &GrayImage, config: &OrbConfig, border: u32) -> Vec<(u32, u32)> {
+    let (w, h) = img.dimensions();
+    let t = config.fast_threshold;
+    let mut scores = vec![0_i32; (w * h) as usize];
+    let pixels = img.as_raw();
+    let offsets = CIRCLE.map(|(dx, dy)| dy as isize * w as isize + dx as isize);
+    for y in border..h - border {
+        for x in border..w - border {
+            let idx = (y * w + x) as usize;
+            let p = pixels[idx] as i32;
+            let at = |i: usize| pixels[(idx as isize + offsets[i]) as usize] as i32;
+
+            // An arc of 9 always contains two adjacent compass points
+            let compass = [at(0), at(4), at(8), at(12)];
+            let brighter = compass.iter().filter(|&&v| v > p + t).count();
+            let darker = compass.iter().filter(|&&v| v < p - t).count();
+            if brighter < 2 && darker < 2 {
+                continue;
+            }
+
+            let circle: [i32; 16] = std::array::from_fn(at);
+            let is_corner = |test: &dyn Fn(i32) -> bool| {
+                let mut run = 0;
+                for i in 0..circle.len() + FAST_ARC - 1 {
+                    if test(circle[i % circle.len()]) {
+                        run += 1;
+                        if run >= FAST_ARC {
+                            return true;
+                        }
+                    } else {
+                        run = 0;
+                    }
+                }
+                false
+            };
+            if is_corner(&|v| v > p + t) || is_corner(&|v| v < p - t) {
+                let bright: i32 = circle.iter().map(|&v| (v - p - t).max(0)).sum();
+                let dark: i32 = circle.iter().map(|&v| (p - v - t).max(0)).sum();
+                scores[idx] = bright.max(dark).max(1);
+            }
+        }
+    }
+
+    let mut corners = Vec::new();
+    for y in border..h - border {
+        for x in border..w - border {
+            let score = scores[(y * w + x) as usize];
+            if score == 0 {
+                continue;
+            }
+            let is_max = (-1..=1).all(|dy: i32| {
+                (-1..=1).all(|dx: i32| {
+                    let (nx, ny) = ((x as i32 + dx) as u32, (y as i32 + dy) as u32);
+                    let other = scores[(ny * w + nx) as usize];
+                    // break ties in favor of the first pixel in scan order
+                    other < score || (other == score && (dy, dx) >= (0, 0))
+                })
+            });
+            if is_max {
+                corners.push((x, y));
+            }
+        }
+    }
+    corners
+}
+
+/// Harris corner response over a window around a pixel, from Sobel gradients.
+fn harris_score(img: &GrayImage, x: u32, y: u32) -> f32 {
+    let px = |x: i32, y: i32| sample(img, x, y);
+    // normalize so that responses are comparable between images, like OpenCV
+    let norm = 1. / (4. * (2 * HARRIS_RADIUS + 1) as f32 * 255.);
+    let (mut a, mut b, mut c) = (0., 0., 0.);
+    for dy in -HARRIS_RADIUS..=HARRIS_RADIUS {
+        for dx in -HARRIS_RADIUS..=HARRIS_RADIUS {
+            let (u, v) = (x as i32 + dx, y as i32 + dy);
+            let ix = (px(u + 1, v - 1) + 2. * px(u + 1, v) + px(u + 1, v + 1))
+                - (px(u - 1, v - 1) + 2. * px(u - 1, v) + px(u - 1, v + 1));
+            let iy = (px(u - 1, v + 1) + 2. * px(u, v + 1) + px(u + 1, v + 1))
+                - (px(u - 1, v - 1) + 2. * px(u, v - 1) + px(u + 1, v - 1));
+            let (ix, iy) = (ix * norm, iy * norm);
+            a += ix * ix;
+            b += iy * iy;
+            c += ix * iy;
+        }
+    }
+    a * b - c * c - HARRIS_K * (a + b) * (a + b)
+}
+
+/// Orientation (in radians) of the vector from a keypoint to the intensity centroid
+/// of the circular patch around it.
+fn intensity_centroid_angle(img: &GrayImage, x: u32, y: u32, radius: i32) -> f32 {
+    let (mut m01, mut m10) = (0., 0.);
+    for dy in -radius..=radius {
+        for dx in -radius..=radius {
+            if dx * dx + dy * dy <= radius * radius {
+                let v = sample(img, x as i32 + dx, y as i32 + dy);
+                m10 += dx as f32 * v;
+                m01 += dy as f32 * v;
+            }
+        }
+    }
+    m01.atan2(m10)
+}
+
+/// 256 binary intensity tests on the smoothed image, with the test pattern rotated by `angle`.
+fn rotated_brief(
+    smoothed: &GrayImage,
+    x: u32,
+    y: u32,
+    angle: f32,
+    pattern: &[[(f32, f32); 2]],
+) -> Desc {
+    let (sin, cos) = angle.sin_cos();
+    let px = |(px, py): (f32, f32)| {
+        let rx = (px * cos - py * sin).round() as i32;
+        let ry = (px * sin + py * cos).round() as i32;
+        sample(smoothed, x as i32 + rx, y as i32 + ry)
+    };
+
+    let mut desc: Desc = [0; 32];
+    for (i, [p, q]) in pattern.iter().enumerate() {
+        if px(*p) < px(*q) {
+            desc[i / 8] |= 1 << (i % 8);
+        }
+    }
+    desc
+}
+
+/// The BRIEF test pattern: 256 pairs of points drawn from an isotropic Gaussian
+/// (Calonder et al., "BRIEF", strategy G II) with a fixed seed.
+fn brief_pattern() -> Vec<[(f32, f32); 2]> {
+    let mut rng = SplitMix64(PATTERN_SEED);
+    let sigma = 31. / 5.;
+    let mut point = || {
+        let (gx, gy) = rng.next_gaussian_pair();
+        let clamp = |v: f32| (v * sigma).round().clamp(-PATTERN_RADIUS, PATTERN_RADIUS);
+        (clamp(gx), clamp(gy))
+    };
+    (0..256).map(|_| [point(), point()]).collect()
+}
+
+/// Separable Gaussian blur over a 7x7 window, replicating the border pixels.
+fn gaussian_blur(img: &GrayImage) -> GrayImage {
+    let kernel: Vec<f32> = (-SMOOTHING_RADIUS..=SMOOTHING_RADIUS)
+        .map(|i| (-(i * i) as f32 / (2. * SMOOTHING_SIGMA * SMOOTHING_SIGMA)).exp())
+        .collect();
+    let sum: f32 = kernel.iter().sum();
+    let kernel: Vec<f32> = kernel.iter().map(|k| k / sum).collect();
+
+    let (w, h) = (img.width() as i32, img.height() as i32);
+    let pixels = img.as_raw();
+    let convolve = |at: &dyn Fn(i32) -> f32| -> f32 {
+        kernel
+            .iter()
+            .zip(-SMOOTHING_RADIUS..)
+            .map(|(k, i)| k * at(i))
+            .sum()
+    };
+    let mut rows = vec![0_f32; pixels.len()];
+    for y in 0..h {
+        for x in 0..w {
+            rows[(y * w + x) as usize] =
+                convolve(&|i| pixels[(y * w + (x + i).clamp(0, w - 1)) as usize] as f32);
+        }
+    }
+    GrayImage::from_fn(w as u32, h as u32, |x, y| {
+        let (x, y) = (x as i32, y as i32);
+        let v = convolve(&|i| rows[((y + i).clamp(0, h - 1) * w + x) as usize]);
+        image::Luma([v.round() as u8])
+    })
+}
+
+/// Pixel value, with coordinates clamped to the image.
+#[inline]
+fn sample(img: &GrayImage, x: i32, y: i32) -> f32 {
+    let x = x.clamp(0, img.width() as i32 - 1) as u32;
+    let y = y.clamp(0, img.height() as i32 - 1) as u32;
+    img.get_pixel(x, y)[0] as f32
+}
+
+/// Small deterministic generator, so that the test pattern never depends on `rand`'s version.
+struct SplitMix64(u64);
+
+impl SplitMix64 {
+    fn next_u64(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^ (z >> 31)
+    }
+
+    /// Uniform in (0, 1].
+    fn next_f32(&mut self) -> f32 {
+        ((self.next_u64() >> 40) as f32 + 1.) / (1_u64 << 24) as f32
+    }
+
+    /// Two independent standard normal samples (Box-Muller).
+    fn next_gaussian_pair(&mut self) -> (f32, f32) {
+        let r = (-2. * self.next_f32().ln()).sqrt();
+        let theta = 2. * std::f32::consts::PI * self.next_f32();
+        (r * theta.cos(), r * theta.sin())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::Vocabulary;
+
+    #[test]
+    fn detect_and_describe() {
+        // Bright squares on a dark background, with an uneven texture on each
+        let img = GrayImage::from_fn(320, 240, |x, y| {
+            let square = (x / 40 + y / 40) % 2 == 0 && x % 40 > 8 && y % 40 > 8;
+            image::Luma([if square {
+                200 + ((x * y) % 50) as u8
+            } else {
+                30
+            }])
+        });
+        let config = OrbConfig {
+            n_features: 200,
+            ..Default::default()
+        };
+        let (kps, descs) = orb_from_image(&img, &config).unwrap();
+        assert!(!kps.is_empty() && kps.len() <= 200);
+        assert_eq!(kps.len(), descs.len());
+        assert!(kps.iter().all(|kp| kp.x >= 0.
+            && kp.y >= 0.
+            && kp.x < 320.
+            && kp.y < 240.
+            && (0. ..360.).contains(&kp.angle)));
+        assert_eq!(orb_from_image(&img, &config).unwrap(), (kps, descs));
+
+        // Nothing is detected outside of the mask
+        let mask = GrayImage::from_fn(320, 240, |x, _| image::Luma([(x < 160) as u8]));
+        let masked = OrbConfig {
+            mask: Some(mask),
+            ..config
+        };
+        let (kps, _) = orb_from_image(&img, &masked).unwrap();
+        assert!(!kps.is_empty() && kps.iter().all(|kp| kp.x < 160.));
+    }
+
+    #[test]
+    fn load_errors() {
+        let config = OrbConfig::default();
+        assert!(matches!(
+            load_img_get_kps("data/missing.jpg", &config),
+            Err(BowErr::Io(_))
+        ));
+        assert!(matches!(
+            load_img_get_kps("README.md", &config),
+            Err(BowErr::UnreadableImage(_))
+        ));
+        assert!(matches!(
+            orb_from_image(&GrayImage::new(0, 0), &config),
+            Err(BowErr::EmptyImage)
+        ));
+    }
+
+    #[test]
+    /// Images of the test sequence should mostly match their neighbours in the sequence.
+    fn sequence_matches() {
+        let config = OrbConfig::default();
+        let path = |dir: &str, name: String| format!("data/{}/{}", dir, name);
+        let features: Vec<Desc> = (290..580)
+            .step_by(29)
+            .flat_map(|i| {
+                load_img_get_kps(path("train", format!("train_{}.jpg", i)), &config).unwrap()
+            })
+            .collect();
+        let voc = Vocabulary::create_seeded(&features, 10, 3, 0).unwrap();
+
+        let bows: Vec<_> = (0..192)
+            .step_by(6)
+            .map(|i| {
+                voc.transform(
+                    &load_img_get_kps(path("test", format!("{}.jpg", i)), &config).unwrap(),
+                )
+                .unwrap()
+            })
+            .collect();
+        let mut close = 0;
+        for (i, bow) in bows.iter().enumerate() {
+            let best = (0..bows.len())
+                .filter(|&j| j != i)
+                .max_by(|&a, &b| {
+                    let score = |i: usize| bow.l1(&bows[i]).unwrap();
+                    score(a).partial_cmp(&score(b)).unwrap()
+                })
+                .unwrap();
+            if (best as i32 - i as i32).abs() <= 1 {
+                close += 1;
+            }
+        }
+        println!("{} of {} images matched a neighbour", close, bows.len());
+        assert!(close * 10 >= bows.len() * 8);
+    }
+}
@@ -1,56 +1,159 @@
 #![cfg(feature = "opencv")]
-use crate::{BowErr, BowResult, Desc};
+use crate::{image_dir::extract_all, image_paths, BowErr, BowResult, Desc, DirOptions, KeyPoint};
 use opencv::{
     self,
-    prelude::{Feature2DTrait, MatTraitConst},
+    prelude::{Feature2DTrait, KeyPointTraitConst, MatTraitConst, MatTraitConstManual},
+};
+use std::{
+    convert::TryInto,
+    path::{Path, PathBuf},
 };
-use std::{convert::TryInto, path::Path};
 
 type CvImage = opencv::prelude::Mat;
 type CvMat = opencv::core::Mat;
 
-/// Extract orb keypoint descriptors from an image. Mostly for testing & example purposes.
-fn orb_from_cvimage(cv_img: &CvImage) -> BowResult<Vec<Desc>> {
+#[derive(Debug, Clone)]
+/// Parameters of the ORB detector. The defaults match OpenCV's `ORB::create()`.
+pub struct OrbConfig {
+    /// Maximum number of features to retain.
+    pub n_features: i32,
+    /// Pyramid decimation ratio, greater than 1.
+    pub scale_factor: f32,
+    /// Number of pyramid levels.
+    pub n_levels: i32,
+    /// Size of the border where features are not detected. Should roughly match `patch_size`.
+    pub edge_threshold: i32,
+    /// Size of the patch used by the oriented BRIEF descriptor.
+    pub patch_size: i32,
+    /// Threshold of the FAST corner detector.
+    pub fast_threshold: i32,
+    /// Optional 8-bit mask of the same size as the image.
+    /// Features are only detected where the mask is non-zero.
+    pub mask: Option<CvMat>,
+}
+
+impl Default for OrbConfig {
+    fn default() -> Self {
+        Self {
+            n_features: 500,
+            scale_factor: 1.2,
+            n_levels: 8,
+            edge_threshold: 31,
+            patch_size: 31,
+            fast_threshold: 20,
+            mask: None,
+        }
+    }
+}
+
+/// Extract orb keypoints and their descriptors from an image. Mostly for testing & example purposes.
+pub(crate) fn orb_from_cvimage(
+    cv_img: &CvImage,
+    config: &OrbConfig,
+) -> BowResult<(Vec<KeyPoint>, Vec<Desc>)> {
+    if cv_img.empty() {
+        return Err(BowErr::EmptyImage);
+    }
+
     // Create detector
-    let mut orb = <dyn opencv::features2d::ORB>::default().unwrap();
+    let mut orb = <dyn opencv::features2d::ORB>::create(
+        config.n_features,
+        config.scale_factor,
+        config.n_levels,
+        config.edge_threshold,
+        0,
+        2,
+        opencv::features2d::ORB_ScoreType::HARRIS_SCORE,
+        config.patch_size,
+        config.fast_threshold,
+    )?;
 
     // Detect keypoints and compute descriptors
     let mut kps = opencv::types::VectorOfKeyPoint::new();
     let mut desc = CvMat::default();
-    let mask = CvMat::default();
-    orb.detect_and_compute(cv_img, &mask, &mut kps, &mut desc, false)?;
-
-    // Copy data from CvMat into descriptor buffer
-    std::panic::catch_unwind(|| {
-        (0..kps.len())
-            .map(|i| {
-                (0..32)
-                    .map(|j| *desc.at_2d::<u8>(i as i32, j).unwrap())
-                    .collect::<Vec<_>>()
-                    .try_into()
-                    .unwrap()
-            })
-            .collect()
-    })
-    .map_err(|_| BowErr::OpenCvDecode)
+    let no_mask = CvMat::default();
+    let mask = config.mask.as_ref().unwrap_or(&no_mask);
+    orb.detect_and_compute(cv_img, mask, &mut kps, &mut desc, false)?;
+
+    let keypoints = kps
+        .iter()
+        .map(|kp| KeyPoint {
+            x: kp.pt().x,
+            y: kp.pt().y,
+            size: kp.size(),
+            angle: kp.angle(),
+            response: kp.response(),
+            octave: kp.octave(),
+        })
+        .collect();
+
+    // Copy data from CvMat into descriptor buffer, once its layout has been checked
+    if kps.is_empty() {
+        return Ok((keypoints, Vec::new()));
+    }
+    if desc.typ() != opencv::core::CV_8UC1 || desc.cols() != 32 || desc.rows() as usize != kps.len()
+    {
+        return Err(BowErr::OpenCvDescriptorMatrix(format!(
+            "expected {} rows of 32 CV_8UC1 columns, got {} rows of {} columns of type {}",
+            kps.len(),
+            desc.rows(),
+            desc.cols(),
+            desc.typ()
+        )));
+    }
+    let descriptors = (0..desc.rows())
+        .map(|i| Ok(desc.at_row::<u8>(i)?.try_into().unwrap()))
+        .collect::<BowResult<Vec<Desc>>>()?;
+
+    Ok((keypoints, descriptors))
 }
 
 /// Use opencv to load an image and extract orb keypoint descriptors.
-pub fn load_img_get_kps<P: AsRef<Path>>(path: P) -> BowResult<Vec<Desc>> {
-    let img: CvImage = opencv::imgcodecs::imread(
-        path.as_ref().to_str().unwrap(),
-        opencv::imgcodecs::IMREAD_GRAYSCALE,
-    )
-    .unwrap();
-    orb_from_cvimage(&img)
+pub fn load_img_get_kps<P: AsRef<Path>>(path: P, config: &OrbConfig) -> BowResult<Vec<Desc>> {
+    load_img_get_features(path, config).map(|res| res.1)
 }
 
-/// Extract orb keypoint descriptors from all images in directory using opencv.
-pub fn all_kps_from_dir<P: AsRef<Path>>(path: P) -> BowResult<Vec<Desc>> {
-    let mut features: Vec<Desc> = Vec::new();
-    for entry in (path.as_ref().read_dir()?).flatten() {
-        println!("Extracting keypoint descriptors from {:?}", entry.path());
-        features.extend(load_img_get_kps(&entry.path())?);
+/// Use opencv to load an image and extract orb keypoints, and their descriptors in the same order.
+pub fn load_img_get_features<P: AsRef<Path>>(
+    path: P,
+    config: &OrbConfig,
+) -> BowResult<(Vec<KeyPoint>, Vec<Desc>)> {
+    orb_from_cvimage(&load_grayscale(path.as_ref())?, config)
+}
+
+/// Use opencv to load an image in grayscale.
+/// Returns Err if the file cannot be opened or decoded, or its path is not UTF-8.
+pub(crate) fn load_grayscale(path: &Path) -> BowResult<CvImage> {
+    let path_str = path
+        .to_str()
+        .ok_or_else(|| BowErr::NonUtf8Path(path.to_owned()))?;
+    // Surface missing files and permission errors as such, before opencv hides them
+    std::fs::File::open(path)?;
+
+    // imread returns an empty matrix instead of an error when it cannot decode a file
+    let img: CvImage = opencv::imgcodecs::imread(path_str, opencv::imgcodecs::IMREAD_GRAYSCALE)?;
+    if img.empty() {
+        return Err(BowErr::UnreadableImage(path.to_owned()));
     }
-    Ok(features)
+    Ok(img)
+}
+
+/// Extract orb keypoint descriptors from all images in a directory using opencv, grouped per image.
+/// Images are listed by [`image_paths`](crate::image_paths), and returned in the same order.
+pub fn features_from_dir<P: AsRef<Path>>(
+    path: P,
+    config: &OrbConfig,
+    options: &DirOptions,
+) -> BowResult<Vec<(PathBuf, Vec<Desc>)>> {
+    let paths = image_paths(path, options)?;
+    extract_all(paths, options.parallel, config, |path, config| {
+        load_img_get_kps(path, config)
+    })
+}
+
+/// Extract orb keypoint descriptors from all images in directory using opencv.
+/// See [`features_from_dir`] to keep track of the image of each descriptor.
+pub fn all_kps_from_dir<P: AsRef<Path>>(path: P, config: &OrbConfig) -> BowResult<Vec<Desc>> {
+    let images = features_from_dir(path, config, &DirOptions::default())?;
+    Ok(images.into_iter().flat_map(|i| i.1).collect())
 }
@@ -0,0 +1,163 @@
+use serde::{Deserialize, Serialize};
+
+use crate::*;
+
+#[derive(Debug, Clone, PartialEq, Default, Serialize, Deserialize)]
+/// Summary of a set of values. All fields are 0 when there are no values.
+pub struct Distribution {
+    /// Number of values.
+    pub count: usize,
+    /// Smallest value.
+    pub min: f32,
+    /// Largest value.
+    pub max: f32,
+    /// Arithmetic mean.
+    pub mean: f32,
+    /// 10th percentile.
+    pub p10: f32,
+    /// 50th percentile.
+    pub median: f32,
+    /// 90th percentile.
+    pub p90: f32,
+    /// 99th percentile.
+    pub p99: f32,
+}
+
+#[derive(Debug, Clone, PartialEq, Default, Serialize, Deserialize)]
+/// Structure and balance of a vocabulary tree. See [`Vocabulary::stats`].
+pub struct VocabularyStats {
+    /// Number of words (leaves).
+    pub num_words: usize,
+    /// Number of non-leaf nodes, excluding the root.
+    pub num_blocks: usize,
+    /// Maximum number of levels the vocabulary was built with.
+    pub levels: usize,
+    /// Branching factor the vocabulary was built with.
+    pub branching_factor: usize,
+    /// Number of nodes at each depth, starting with the root at depth 0.
+    pub nodes_per_level: Vec<usize>,
+    /// Number of words at each depth, starting with the root at depth 0.
+    /// Words above the deepest level are clusters which could not be split further.
+    pub words_per_level: Vec<usize>,
+    /// Number of features the vocabulary was trained on.
+    pub training_features: usize,
+    /// Number of training features per word.
+    pub cluster_sizes: Distribution,
+    /// Number of words by cluster size, in powers of two: `cluster_size_histogram[i]` counts the
+    /// words with between `2^i` and `2^(i+1) - 1` training features.
+    pub cluster_size_histogram: Vec<usize>,
+    /// Word weights.
+    pub weights: Distribution,
+    /// Depth of the words.
+    pub word_depths: Distribution,
+    /// Imbalance factor `n * sum(p_i^2)` of the `n` words, where `p_i` is the fraction of the
+    /// training features in word `i` (Jégou et al., 2010). It is 1 when all words are equally
+    /// large, and grows as features concentrate in fewer words, which makes them less
+    /// discriminative. 0 for an empty vocabulary.
+    pub imbalance: f32,
+}
+
+impl Distribution {
+    /// Summarize the values. They need not be sorted.
+    pub fn new<I: IntoIterator<Item = f32>>(values: I) -> Self {
+        let mut values: Vec<f32> = values.into_iter().collect();
+        if values.is_empty() {
+            return Self::default();
+        }
+        values.sort_by(|a, b| a.total_cmp(b));
+        let n = values.len();
+        let percentile = |p: f32| values[((n - 1) as f32 * p).round() as usize];
+        Self {
+            count: n,
+            min: values[0],
+            max: values[n - 1],
+            mean: values.iter().sum::<f32>() / n as f32,
+            p10: percentile(0.1),
+            median: percentile(0.5),
+            p90: percentile(0.9),
+            p99: percentile(0.99),
+        }
+    }
+}
+
+/// Vocabulary statistics
+impl Vocabulary {
+    /// Statistics of the tree's structure, word sizes and weights.
+    pub fn stats(&self) -> VocabularyStats {
+        let mut words_per_level = vec![0; self.nodes_per_level.len()];
+        let mut cluster_size_histogram = Vec::new();
+        for word in self.words() {
+            words_per_level[word.depth()] += 1;
+            let size = word.cluster_size();
+            if size > 0 {
+                let bucket = (usize::BITS - 1 - size.leading_zeros()) as usize;
+                if bucket >= cluster_size_histogram.len() {
+                    cluster_size_histogram.resize(bucket + 1, 0);
+                }
+                cluster_size_histogram[bucket] += 1;
+            }
+        }
+
+        let training_features = self.root().map_or(0, |root| root.cluster_size());
+        let imbalance = match training_features {
+            0 => 0.,
+            total => {
+                let sum_sq: f64 = self
+                    .words()
+                    .map(|w| (w.cluster_size() as f64 / total as f64).powi(2))
+                    .sum();
+                (self.num_leaves as f64 * sum_sq) as f32
+            }
+        };
+
+        VocabularyStats {
+            num_words: self.num_leaves,
+            num_blocks: self.num_blocks,
+            levels: self.levels,
+            branching_factor: self.k,
+            nodes_per_level: self.nodes_per_level.clone(),
+            words_per_level,
+            training_features,
+            cluster_sizes: Distribution::new(self.words().map(|w| w.cluster_size() as f32)),
+            cluster_size_histogram,
+            weights: Distribution::new(self.words().filter_map(|w| w.weight())),
+            word_depths: Distribution::new(self.words().map(|w| w.depth() as f32)),
+            imbalance,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use rand::{thread_rng, Rng};
+
+    #[test]
+    fn vocabulary_stats() {
+        let features: Vec<Desc> = (0..2000).map(|_| thread_rng().gen()).collect();
+        let voc = Vocabulary::create(&features, 4, 3).unwrap();
+        let stats = voc.stats();
+        assert_eq!(stats.training_features, 2000);
+        assert_eq!(stats.cluster_sizes.count, voc.num_words());
+        assert_eq!(stats.words_per_level.iter().sum::<usize>(), voc.num_words());
+        assert_eq!(
+            stats.cluster_size_histogram.iter().sum::<usize>(),
+            voc.num_words()
+        );
+        assert!(stats.cluster_sizes.min <= stats.cluster_sizes.median);
+        assert!(stats.cluster_sizes.median <= stats.cluster_sizes.max);
+        assert_eq!((stats.weights.min, stats.weights.max), (1., 1.));
+        assert!(stats.imbalance >= 1.);
+
+        // Empty vocabularies have empty statistics, and can be printed
+        let empty = Vocabulary::default();
+        assert_eq!(empty.stats().cluster_sizes, Distribution::default());
+        assert_eq!(empty.stats().imbalance, 0.);
+        assert!(!format!("{:?}", empty).is_empty());
+
+        // NaN is sorted after every number
+        let values = Distribution::new([3., f32::NAN, 1., 2.]);
+        assert_eq!((values.min, values.median), (1., 3.));
+        assert!(values.max.is_nan());
+    }
+}
@@ -0,0 +1,346 @@
+//! Search over vocabulary training parameters, scored by retrieval quality on a validation set.
+//!
+//! Every combination of the parameters in a [`SweepGrid`] is tried. Clustering is the expensive
+//! step, so each vocabulary is trained once and then reused for every weighting and scoring.
+use std::{io::Write, time::Instant};
+
+use serde::{Deserialize, Serialize};
+
+use crate::*;
+
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone, Copy, Default)]
+/// How words are weighted after clustering.
+pub enum Weighting {
+    /// All words have weight 1.
+    #[default]
+    Tf,
+    /// Words are weighted by inverse document frequency in the training images,
+    /// see [`Vocabulary::set_idf_weights`].
+    TfIdf,
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone, Copy, Default)]
+/// Metric by which the best configuration of a sweep is selected.
+pub enum Criterion {
+    /// [`Evaluation::mean_average_precision`].
+    #[default]
+    MeanAveragePrecision,
+    /// [`Evaluation::pr_auc`].
+    PrAuc,
+    /// Recall@k for the given k, which must be in [`SweepGrid::recall_at`].
+    RecallAt(usize),
+}
+
+impl Criterion {
+    /// Value of the metric in an evaluation. Higher is better.
+    pub fn value(&self, eval: &Evaluation) -> f32 {
+        match self {
+            Criterion::MeanAveragePrecision => eval.mean_average_precision,
+            Criterion::PrAuc => eval.pr_auc,
+            Criterion::RecallAt(k) => eval
+                .recall_at_k
+                .iter()
+                .find(|(n, _)| n == k)
+                .map_or(0., |r| r.1),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+/// Values to try for each parameter. Every combination is evaluated.
+pub struct SweepGrid {
+    /// Branching factors.
+    pub k: Vec<usize>,
+    /// Numbers of levels.
+    pub l: Vec<usize>,
+    /// k-means initialization methods.
+    pub init: Vec<ClusterInitMethod>,
+    /// Minimum sizes of clusters which are split further, see [`TrainingParams`].
+    pub min_cluster_size: Vec<usize>,
+    /// Word weightings.
+    pub weighting: Vec<Weighting>,
+    /// Scoring functions between BoW vectors.
+    pub scoring: Vec<Scoring>,
+    /// Seed for training every vocabulary, for reproducible sweeps.
+    pub seed: Option<u64>,
+    /// Values of k for which recall@k is computed.
+    pub recall_at: Vec<usize>,
+    /// Metric the best configuration is selected by.
+    pub criterion: Criterion,
+}
+
+impl Default for SweepGrid {
+    fn default() -> Self {
+        Self {
+            k: vec![6, 8, 10],
+            l: vec![3, 4, 5],
+            init: vec![ClusterInitMethod::KMeansPP],
+            min_cluster_size: vec![2],
+            weighting: vec![Weighting::Tf],
+            scoring: vec![Scoring::L1],
+            seed: None,
+            recall_at: vec![1, 5, 10],
+            criterion: Criterion::MeanAveragePrecision,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone, Copy)]
+/// One combination of parameters of a [`SweepGrid`].
+pub struct SweepConfig {
+    /// Parameters the vocabulary was trained with.
+    pub training: TrainingParams,
+    /// Weighting of the words after training.
+    pub weighting: Weighting,
+    /// Scoring function between the BoW vectors of queries and database images.
+    pub scoring: Scoring,
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+/// Cost and retrieval quality of one configuration.
+pub struct SweepResult {
+    /// Parameters of the configuration.
+    pub config: SweepConfig,
+    /// Time to build the vocabulary and set its weights, in seconds.
+    pub train_secs: f64,
+    /// Number of words of the vocabulary.
+    pub num_words: usize,
+    /// Mean time to transform one validation image, in seconds.
+    pub transform_secs: f64,
+    /// Retrieval metrics on the validation set.
+    pub evaluation: Evaluation,
+}
+
+#[derive(Debug, Clone)]
+/// Results of [`sweep()`].
+pub struct Sweep {
+    /// Results of every configuration, in the order they were evaluated.
+    pub results: Vec<SweepResult>,
+    /// Index into `results` of the best configuration. The first one wins ties.
+    pub best: usize,
+    /// Vocabulary of the best configuration, with its weighting applied.
+    pub vocabulary: Vocabulary,
+}
+
+/// Train a vocabulary for every configuration in `grid` on the features of the `training`
+/// images, and evaluate retrieval of the `queries` among the `database` images with `truth`.
+/// Returns Err if the grid is empty or invalid, or any image to transform has no features.
+pub fn sweep<D: AsRef<[Desc]>>(
+    training: &[D],
+    queries: &[D],
+    database: &[D],
+    truth: &GroundTruth,
+    grid: &SweepGrid,
+) -> BowResult<Sweep> {
+    if grid.k.is_empty()
+        || grid.l.is_empty()
+        || grid.init.is_empty()
+        || grid.min_cluster_size.is_empty()
+        || grid.weighting.is_empty()
+        || grid.scoring.is_empty()
+    {
+        return Err(BowErr::InvalidParams(
+            "sweep grid needs at least one value of each parameter",
+        ));
+    }
+    if let Criterion::RecallAt(k) = grid.criterion {
+        if !grid.recall_at.contains(&k) {
+            return Err(BowErr::InvalidParams(
+                "sweep criterion recall@k needs k in recall_at",
+            ));
+        }
+    }
+    let features: Vec<Desc> = training
+        .iter()
+        .flat_map(|f| f.as_ref().iter().cloned())
+        .collect();
+    let idf_images: Vec<&[Desc]> = training
+        .iter()
+        .map(|f| f.as_ref())
+        .filter(|f| !f.is_empty())
+        .collect();
+
+    let mut results: Vec<SweepResult> = Vec::new();
+    let mut best: Option<(usize, f32, Vocabulary)> = None;
+    for &k in grid.k.iter() {
+        for &l in grid.l.iter() {
+            for &init in grid.init.iter() {
+                for &min_cluster_size in grid.min_cluster_size.iter() {
+                    let params = TrainingParams {
+                        k,
+                        l,
+                        init,
+                        min_cluster_size,
+                        seed: grid.seed,
+                    };
+                    let start = Instant::now();
+                    let trained = Vocabulary::create_with_params(&features, &params)?;
+                    let cluster_secs = start.elapsed().as_secs_f64();
+
+                    for &weighting in grid.weighting.iter() {
+                        let start = Instant::now();
+                        let mut voc = trained.clone();
+                        if weighting == Weighting::TfIdf {
+                            voc.set_idf_weights(&idf_images)?;
+                        }
+                        let train_secs = cluster_secs + start.elapsed().as_secs_f64();
+
+                        let start = Instant::now();
+                        let transform = |images: &[D]| -> BowResult<Vec<BoW>> {
+                            images.iter().map(|f| voc.transform(f.as_ref())).collect()
+                        };
+                        let query_bows = transform(queries)?;
+                        let database_bows = transform(database)?;
+                        let transform_secs = start.elapsed().as_secs_f64()
+                            / (queries.len() + database.len()).max(1) as f64;
+
+                        for &scoring in grid.scoring.iter() {
+                            let evaluation = evaluate(
+                                &query_bows,
+                                &database_bows,
+                                truth,
+                                |a, b| a.score(b, scoring),
+                                &grid.recall_at,
+                            )?;
+                            let value = grid.criterion.value(&evaluation);
+                            if best.as_ref().map_or(true, |b| value > b.1) {
+                                best = Some((results.len(), value, voc.clone()));
+                            }
+                            results.push(SweepResult {
+                                config: SweepConfig {
+                                    training: params,
+                                    weighting,
+                                    scoring,
+                                },
+                                train_secs,
+                                num_words: voc.num_words(),
+                                transform_secs,
+                                evaluation,
+                            });
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    let (best, _, vocabulary) = best.expect("the grid has at least one configuration");
+    Ok(Sweep {
+        results,
+        best,
+        vocabulary,
+    })
+}
+
+impl Sweep {
+    /// Result of the best configuration.
+    pub fn best(&self) -> &SweepResult {
+        &self.results[self.best]
+    }
+
+    /// Write one CSV row per configuration, with a header. Recall@k columns follow the
+    /// order of [`SweepGrid::recall_at`].
+    pub fn write_csv<W: Write>(&self, mut w: W) -> BowResult<()> {
+        write!(
+            w,
+            "k,l,init,min_cluster_size,weighting,scoring,train_secs,num_words,\
+             transform_secs,num_queries,map,pr_auc"
+        )?;
+        if let Some(first) = self.results.first() {
+            for (k, _) in first.evaluation.recall_at_k.iter() {
+                write!(w, ",recall@{}", k)?;
+            }
+        }
+        writeln!(w)?;
+        for r in self.results.iter() {
+            let (c, e) = (&r.config, &r.evaluation);
+            write!(
+                w,
+                "{},{},{:?},{},{:?},{:?},{},{},{},{},{},{}",
+                c.training.k,
+                c.training.l,
+                c.training.init,
+                c.training.min_cluster_size,
+                c.weighting,
+                c.scoring,
+                r.train_secs,
+                r.num_words,
+                r.transform_secs,
+                e.num_queries,
+                e.mean_average_precision,
+                e.pr_auc
+            )?;
+            for (_, recall) in e.recall_at_k.iter() {
+                write!(w, ",{}", recall)?;
+            }
+            writeln!(w)?;
+        }
+        w.flush()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use rand::{rngs::StdRng, Rng, SeedableRng};
+
+    #[test]
+    fn sweep_grid() {
+        // Each place has its own features, seen in noisy versions by every image of it
+        let mut rng = StdRng::seed_from_u64(7);
+        let places: Vec<Vec<Desc>> = (0..6)
+            .map(|_| (0..40).map(|_| rng.gen()).collect())
+            .collect();
+        let mut image = |place: usize| -> Vec<Desc> {
+            places[place]
+                .iter()
+                .map(|d| {
+                    let mut d = *d;
+                    d[rng.gen_range(0..32)] ^= 1 << rng.gen_range(0..8);
+                    d
+                })
+                .collect()
+        };
+        // Queries are other images of the places than those in the database
+        let images: Vec<Vec<Desc>> = (0..12).map(|i| image(i / 2)).collect();
+        let queries: Vec<Vec<Desc>> = (0..6).map(&mut image).collect();
+        let truth = GroundTruth::Sequence {
+            queries: (0..6).collect(),
+            database: (0..12).map(|i| i / 2).collect(),
+            tolerance: 0,
+            ignore_within: None,
+        };
+        let grid = SweepGrid {
+            k: vec![2, 8],
+            l: vec![1, 2],
+            init: vec![ClusterInitMethod::Random, ClusterInitMethod::KMeansPP],
+            weighting: vec![Weighting::Tf, Weighting::TfIdf],
+            scoring: vec![Scoring::L1, Scoring::Bhattacharyya],
+            seed: Some(3),
+            criterion: Criterion::RecallAt(1),
+            ..Default::default()
+        };
+        let result = sweep(&images, &queries, &images, &truth, &grid).unwrap();
+        assert_eq!(result.results.len(), 32);
+        assert_eq!(result.best().evaluation.recall_at_k[0], (1, 1.));
+        // Two words cannot tell six places apart
+        let coarse: Vec<&SweepResult> =
+            result.results.iter().filter(|r| r.num_words == 2).collect();
+        assert_eq!(coarse.len(), 8);
+        assert!(coarse.iter().all(|r| r.evaluation.recall_at_k[0].1 < 1.));
+        assert_eq!(result.vocabulary.num_words(), result.best().num_words);
+
+        let mut csv = Vec::new();
+        result.write_csv(&mut csv).unwrap();
+        let csv = String::from_utf8(csv).unwrap();
+        assert_eq!(csv.lines().count(), 33);
+        assert!(csv.lines().next().unwrap().ends_with("recall@10"));
+
+        let empty = SweepGrid {
+            scoring: vec![],
+            ..Default::default()
+        };
+        assert!(sweep(&images, &images, &images, &truth, &empty).is_err());
+    }
+}
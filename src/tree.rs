@@ -0,0 +1,283 @@
+use std::{fmt, sync::OnceLock};
+
+use crate::vocab::NodeId;
+use crate::*;
+
+#[derive(Clone, Copy)]
+/// Read-only view of a node of the vocabulary tree: the root, an inner node, or a word.
+///
+/// Nodes are identified by their stable id (see [`Vocabulary::level_nodes`]).
+pub struct Node<'a> {
+    voc: &'a Vocabulary,
+    id: usize,
+}
+
+impl PartialEq for Node<'_> {
+    fn eq(&self, other: &Self) -> bool {
+        std::ptr::eq(self.voc, other.voc) && self.id == other.id
+    }
+}
+
+impl fmt::Debug for Node<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Node")
+            .field("id", &self.id)
+            .field("depth", &self.depth())
+            .field("word", &self.word_id())
+            .field("cluster size", &self.cluster_size())
+            .finish()
+    }
+}
+
+/// Tree introspection
+impl Vocabulary {
+    /// The root of the tree, or None if the vocabulary is empty.
+    pub fn root(&self) -> Option<Node<'_>> {
+        self.node(0)
+    }
+
+    /// The node with stable id `id`, if there is one.
+    pub fn node(&self, id: usize) -> Option<Node<'_>> {
+        match id < self.index().parents.len() {
+            true => Some(Node { voc: self, id }),
+            false => None,
+        }
+    }
+
+    /// All nodes, in stable id order (breadth first, starting with the root).
+    pub fn nodes(&self) -> impl Iterator<Item = Node<'_>> {
+        (0..self.index().parents.len()).map(move |id| Node { voc: self, id })
+    }
+
+    /// The node of the word with id `word_id`, if there is one.
+    pub fn word(&self, word_id: usize) -> Option<Node<'_>> {
+        let id = *self.index().word_nodes.get(word_id)?;
+        Some(Node { voc: self, id })
+    }
+
+    /// All words, in word id order.
+    pub fn words(&self) -> impl Iterator<Item = Node<'_>> {
+        let index = self.index();
+        index
+            .word_nodes
+            .iter()
+            .map(move |&id| Node { voc: self, id })
+    }
+
+    /// Id of the word a descriptor is assigned to by [`Self::transform`],
+    /// or None if the vocabulary is empty.
+    pub fn word_of(&self, feature: &Desc) -> Option<usize> {
+        if self.blocks.is_empty() {
+            return None;
+        }
+        let (children, leaf) = self.leaf_of(feature);
+        match children.ids[leaf] {
+            NodeId::Leaf(word_id) => Some(word_id),
+            NodeId::Block(_) => None,
+        }
+    }
+
+    /// Stable node id of the block with index `block` in `Vocabulary::blocks`.
+    #[cfg_attr(not(feature = "opencv"), allow(dead_code))]
+    pub(crate) fn block_node(&self, block: usize) -> usize {
+        self.index().block_nodes[block]
+    }
+
+    fn index(&self) -> &TreeIndex {
+        self.tree_index.0.get_or_init(|| TreeIndex::new(self))
+    }
+}
+
+impl<'a> Node<'a> {
+    /// Stable id of the node.
+    pub fn id(&self) -> usize {
+        self.id
+    }
+
+    /// Depth of the node, with 0 for the root.
+    pub fn depth(&self) -> usize {
+        let mut end = 0;
+        for (level, n) in self.voc.nodes_per_level.iter().enumerate() {
+            end += n;
+            if self.id < end {
+                return level;
+            }
+        }
+        unreachable!("node ids are below the number of nodes")
+    }
+
+    /// The node's parent, or None for the root.
+    pub fn parent(&self) -> Option<Node<'a>> {
+        match self.id {
+            0 => None,
+            id => Some(Node {
+                voc: self.voc,
+                id: self.voc.index().parents[id],
+            }),
+        }
+    }
+
+    /// The node's children, which are empty for words.
+    pub fn children(&self) -> impl Iterator<Item = Node<'a>> {
+        let voc = self.voc;
+        let ids: &[usize] = match self.block_id() {
+            Some(b) => &voc.blocks[b].children.nodes,
+            None => &[],
+        };
+        ids.iter().map(move |&id| Node { voc, id })
+    }
+
+    /// The word id of the node, or None if it is not a word.
+    pub fn word_id(&self) -> Option<usize> {
+        match self.location().map(|(children, c)| &children.ids[c]) {
+            Some(NodeId::Leaf(word_id)) => Some(*word_id),
+            _ => None,
+        }
+    }
+
+    /// Returns true if the node is a word (leaf).
+    pub fn is_word(&self) -> bool {
+        self.word_id().is_some()
+    }
+
+    /// Centroid descriptor of the node's cluster, or None for the root.
+    pub fn centroid(&self) -> Option<&'a Desc> {
+        self.location().map(|(children, c)| &children.features[c])
+    }
+
+    /// Weight of the node, or None for the root. Only word weights are used by transforms.
+    pub fn weight(&self) -> Option<f32> {
+        self.location().map(|(children, c)| children.weights[c])
+    }
+
+    /// Number of training features in the node's cluster.
+    /// For the root, this is the total number of training features.
+    pub fn cluster_size(&self) -> usize {
+        match self.location() {
+            Some((children, c)) => children.cluster_size[c],
+            None => self.voc.blocks[0].children.cluster_size.iter().sum(),
+        }
+    }
+
+    /// Parent block's children and the index of this node among them, or None for the root.
+    fn location(&self) -> Option<(&'a vocab::Children, usize)> {
+        match self.id {
+            0 => None,
+            _ => {
+                let (b, c) = self.voc.index().locations[self.id];
+                Some((&self.voc.blocks[b].children, c))
+            }
+        }
+    }
+
+    /// Index into `Vocabulary::blocks` of the block holding the node's children, if any.
+    fn block_id(&self) -> Option<usize> {
+        match self.location().map(|(children, c)| &children.ids[c]) {
+            None => Some(0),
+            Some(NodeId::Block(b)) => Some(*b),
+            Some(NodeId::Leaf(_)) => None,
+        }
+    }
+}
+
+#[derive(Clone, Default)]
+/// Lookup tables from stable node ids into the tree, built on first use.
+/// Not serialized, and ignored by comparisons since it is derived from the tree.
+pub(crate) struct TreeIndexCache(OnceLock<TreeIndex>);
+
+impl PartialEq for TreeIndexCache {
+    fn eq(&self, _other: &Self) -> bool {
+        true
+    }
+}
+
+#[derive(Clone, Default)]
+struct TreeIndex {
+    /// (block, child) of each node id. Unused for the root.
+    locations: Vec<(usize, usize)>,
+    /// Parent node id of each node id. Unused for the root.
+    parents: Vec<usize>,
+    /// Node id of each word id.
+    word_nodes: Vec<usize>,
+    /// Node id of each block, by index in `Vocabulary::blocks`.
+    block_nodes: Vec<usize>,
+}
+
+impl TreeIndex {
+    fn new(voc: &Vocabulary) -> Self {
+        let num_nodes: usize = voc.nodes_per_level.iter().sum();
+        let mut index = Self {
+            locations: vec![(0, 0); num_nodes],
+            parents: vec![0; num_nodes],
+            word_nodes: vec![0; voc.num_leaves],
+            block_nodes: vec![0; voc.blocks.len()],
+        };
+        if voc.blocks.is_empty() {
+            return index;
+        }
+
+        // (block, node id of the block)
+        let mut stack = vec![(0, 0)];
+        while let Some((b, parent)) = stack.pop() {
+            let children = &voc.blocks[b].children;
+            for (c, (&id, node)) in children.nodes.iter().zip(&children.ids).enumerate() {
+                index.locations[id] = (b, c);
+                index.parents[id] = parent;
+                match node {
+                    NodeId::Block(child) => {
+                        index.block_nodes[*child] = id;
+                        stack.push((*child, id));
+                    }
+                    NodeId::Leaf(word_id) => index.word_nodes[*word_id] = id,
+                }
+            }
+        }
+        index
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use rand::{thread_rng, Rng};
+
+    #[test]
+    fn introspection() {
+        let features: Vec<Desc> = (0..2000).map(|_| thread_rng().gen()).collect();
+        let voc = Vocabulary::create(&features, 5, 3).unwrap();
+
+        let root = voc.root().unwrap();
+        assert_eq!(
+            (root.depth(), root.parent(), root.centroid()),
+            (0, None, None)
+        );
+        assert_eq!(root.cluster_size(), features.len());
+        assert_eq!(voc.nodes().count(), voc.num_blocks() + voc.num_words() + 1);
+        assert!(voc.nodes().enumerate().all(|(i, n)| n.id() == i));
+
+        for (word_id, word) in voc.words().enumerate() {
+            assert_eq!(word.word_id(), Some(word_id));
+            assert_eq!(word.children().count(), 0);
+            assert_eq!(word.weight(), Some(1.));
+            let parent = word.parent().unwrap();
+            assert_eq!(parent.depth() + 1, word.depth());
+            assert!(parent.children().any(|c| c == word));
+        }
+
+        // Children partition their parent's training features
+        for node in voc.nodes().filter(|n| !n.is_word()) {
+            let sizes: usize = node.children().map(|c| c.cluster_size()).sum();
+            assert_eq!(sizes, node.cluster_size());
+        }
+
+        for feature in features[..100].iter() {
+            let word = voc.word(voc.word_of(feature).unwrap()).unwrap();
+            let bow = voc.transform(&[*feature]).unwrap();
+            assert_eq!(bow.0[word.word_id().unwrap()], 1.);
+        }
+
+        let empty = Vocabulary::default();
+        assert!(empty.root().is_none() && empty.word_of(&features[0]).is_none());
+        assert_eq!(empty.nodes().count(), 0);
+    }
+}
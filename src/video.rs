@@ -0,0 +1,267 @@
+#![cfg(feature = "opencv")]
+use crate::{
+    image_paths, opencv_utils::orb_from_cvimage, BoW, BowErr, BowResult, Desc, DirOptions,
+    KeyPoint, OrbConfig, Vocabulary,
+};
+use opencv::{
+    self,
+    prelude::{MatTraitConst, VideoCaptureTrait, VideoCaptureTraitConst},
+};
+use std::path::Path;
+
+type CvMat = opencv::core::Mat;
+
+/// Integer file names at least this large are taken to be nanoseconds, as in ROS bags
+/// and EuRoC-style datasets. Smaller numbers are seconds.
+const NANOSECOND_STAMPS: f64 = 1e12;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+/// Which frames of a video or image sequence to extract features from.
+pub enum FrameSampling {
+    /// Every frame.
+    All,
+    /// Every nth frame, starting with the first.
+    EveryNth(usize),
+    /// At most this many frames per second of recording.
+    Rate(f64),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+/// Features of one frame of a video or image sequence.
+pub struct Frame {
+    /// Position of the frame in the video or sequence, counting frames which were skipped.
+    pub index: usize,
+    /// Time of the frame in seconds, from the start of a video or from the file name in a
+    /// sequence. Sequences without timestamps in their file names use the index.
+    pub timestamp: f64,
+    /// Orb keypoints of the frame.
+    pub keypoints: Vec<KeyPoint>,
+    /// Descriptors of the keypoints, in the same order.
+    pub descriptors: Vec<Desc>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+/// Bag of words of one frame, ready to be added to a database.
+pub struct FrameBoW {
+    /// See [`Frame::index`].
+    pub index: usize,
+    /// See [`Frame::timestamp`].
+    pub timestamp: f64,
+    /// Bag of words of the frame's descriptors.
+    pub bow: BoW,
+}
+
+impl FrameSampling {
+    fn validate(self) -> BowResult<()> {
+        match self {
+            FrameSampling::EveryNth(0) => Err(BowErr::InvalidParams(
+                "frames must be sampled every n >= 1 frames",
+            )),
+            FrameSampling::Rate(rate) if rate.is_nan() || rate <= 0. => Err(BowErr::InvalidParams(
+                "frames must be sampled at a positive rate",
+            )),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Keeps track of which frames to sample, given their index and timestamp in order.
+struct Sampler {
+    sampling: FrameSampling,
+    next_time: Option<f64>,
+}
+
+impl Sampler {
+    fn new(sampling: FrameSampling) -> BowResult<Self> {
+        sampling.validate()?;
+        Ok(Self {
+            sampling,
+            next_time: None,
+        })
+    }
+
+    fn keep(&mut self, index: usize, timestamp: f64) -> bool {
+        match self.sampling {
+            FrameSampling::All => true,
+            FrameSampling::EveryNth(n) => index % n == 0,
+            FrameSampling::Rate(rate) => match self.next_time {
+                Some(next) if timestamp < next => false,
+                // Step from the previous sample, so the rate does not drift with frame jitter
+                Some(next) => {
+                    let steps = ((timestamp - next) * rate).floor() + 1.;
+                    self.next_time = Some(next + steps / rate);
+                    true
+                }
+                None => {
+                    self.next_time = Some(timestamp + 1. / rate);
+                    true
+                }
+            },
+        }
+    }
+}
+
+/// Use opencv to decode a video file (or anything else `VideoCapture` can open) and extract orb
+/// features from the sampled frames. Frames are converted to grayscale first.
+pub fn video_features<P: AsRef<Path>>(
+    path: P,
+    sampling: FrameSampling,
+    config: &OrbConfig,
+) -> BowResult<Vec<Frame>> {
+    let path = path.as_ref();
+    let path_str = path
+        .to_str()
+        .ok_or_else(|| BowErr::NonUtf8Path(path.to_owned()))?;
+    std::fs::File::open(path)?;
+    let mut capture = opencv::videoio::VideoCapture::from_file(path_str, opencv::videoio::CAP_ANY)?;
+    if !capture.is_opened()? {
+        return Err(BowErr::UnreadableImage(path.to_owned()));
+    }
+
+    let mut sampler = Sampler::new(sampling)?;
+    let mut frames = Vec::new();
+    let (mut image, mut gray) = (CvMat::default(), CvMat::default());
+    // Only decode the frames which are kept
+    for index in 0.. {
+        if !capture.grab()? {
+            break;
+        }
+        let timestamp = capture.get(opencv::videoio::CAP_PROP_POS_MSEC)? / 1000.;
+        if !sampler.keep(index, timestamp) || !capture.retrieve(&mut image, 0)? {
+            continue;
+        }
+        let frame = if image.channels() > 1 {
+            opencv::imgproc::cvt_color(&image, &mut gray, opencv::imgproc::COLOR_BGR2GRAY, 0)?;
+            &gray
+        } else {
+            &image
+        };
+        let (keypoints, descriptors) = orb_from_cvimage(frame, config)?;
+        frames.push(Frame {
+            index,
+            timestamp,
+            keypoints,
+            descriptors,
+        });
+    }
+    Ok(frames)
+}
+
+/// Use opencv to extract orb features from the sampled frames of a directory of images.
+///
+/// If all file names are finite numbers, they are taken as timestamps and frames are ordered by them:
+/// integers of 13 digits or more are nanoseconds (as written by ROS tools), other numbers are
+/// seconds. Otherwise frames are in sorted file name order, and timestamped by their index.
+pub fn sequence_features<P: AsRef<Path>>(
+    dir: P,
+    sampling: FrameSampling,
+    config: &OrbConfig,
+) -> BowResult<Vec<Frame>> {
+    let paths = image_paths(dir, &DirOptions::default())?;
+    let stamps: Option<Vec<f64>> = paths.iter().map(|p| file_timestamp(p)).collect();
+    let mut frames: Vec<(f64, &Path)> = match stamps {
+        Some(stamps) => stamps
+            .into_iter()
+            .zip(paths.iter().map(|p| p.as_path()))
+            .collect(),
+        None => paths
+            .iter()
+            .enumerate()
+            .map(|(i, p)| (i as f64, p.as_path()))
+            .collect(),
+    };
+    frames.sort_by(|a, b| a.0.total_cmp(&b.0));
+
+    let mut sampler = Sampler::new(sampling)?;
+    let mut features = Vec::new();
+    for (index, (timestamp, path)) in frames.into_iter().enumerate() {
+        if sampler.keep(index, timestamp) {
+            let (keypoints, descriptors) = crate::load_img_get_features(path, config)?;
+            features.push(Frame {
+                index,
+                timestamp,
+                keypoints,
+                descriptors,
+            });
+        }
+    }
+    Ok(features)
+}
+
+/// Timestamp in seconds encoded in a file name, if it is a finite number.
+fn file_timestamp(path: &Path) -> Option<f64> {
+    let stem = path.file_stem()?.to_str()?;
+    let stamp: f64 = stem.parse().ok().filter(|s: &f64| s.is_finite())?;
+    let is_integer = stem.bytes().all(|b| b.is_ascii_digit());
+    Some(if is_integer && stamp >= NANOSECOND_STAMPS {
+        stamp / 1e9
+    } else {
+        stamp
+    })
+}
+
+/// Video helpers
+impl Vocabulary {
+    /// Transform frames into bags of words, keeping their index and timestamp.
+    /// Frames without features are skipped.
+    pub fn transform_frames(&self, frames: &[Frame]) -> BowResult<Vec<FrameBoW>> {
+        let mut bows = Vec::with_capacity(frames.len());
+        for frame in frames {
+            match self.transform(&frame.descriptors) {
+                Ok(bow) => bows.push(FrameBoW {
+                    index: frame.index,
+                    timestamp: frame.timestamp,
+                    bow,
+                }),
+                Err(BowErr::NoFeatures) => continue,
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(bows)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn sampling() {
+        let kept = |sampling, stamps: &[f64]| -> Vec<usize> {
+            let mut sampler = Sampler::new(sampling).unwrap();
+            (0..stamps.len())
+                .filter(|&i| sampler.keep(i, stamps[i]))
+                .collect()
+        };
+        let stamps: Vec<f64> = (0..10).map(|i| i as f64 * 0.25).collect();
+        assert_eq!(kept(FrameSampling::EveryNth(4), &stamps), [0, 4, 8]);
+        assert_eq!(kept(FrameSampling::Rate(1.), &stamps), [0, 4, 8]);
+        assert_eq!(kept(FrameSampling::All, &stamps).len(), 10);
+        assert!(Sampler::new(FrameSampling::Rate(0.)).is_err());
+        assert!(Sampler::new(FrameSampling::EveryNth(0)).is_err());
+
+        let ns = file_timestamp(Path::new("cam0/1403636579763555584.png")).unwrap();
+        assert!((ns - 1403636579.763_555_6).abs() < 1e-6);
+        assert_eq!(
+            file_timestamp(Path::new("1305031102.175304.png")),
+            Some(1305031102.175304)
+        );
+        assert_eq!(file_timestamp(Path::new("frame_1.png")), None);
+        assert_eq!(file_timestamp(Path::new("NaN.png")), None);
+        assert_eq!(file_timestamp(Path::new("inf.jpg")), None);
+    }
+
+    #[test]
+    fn image_sequence() {
+        let config = OrbConfig::default();
+        let frames = sequence_features("data/test", FrameSampling::Rate(0.1), &config).unwrap();
+        assert_eq!(frames.len(), 20);
+        assert_eq!((frames[1].index, frames[1].timestamp), (10, 10.));
+
+        let features: Vec<Desc> = frames.iter().flat_map(|f| f.descriptors.clone()).collect();
+        let voc = Vocabulary::create(&features, 8, 3).unwrap();
+        let bows = voc.transform_frames(&frames).unwrap();
+        assert_eq!(bows.len(), frames.len());
+        assert_eq!(bows[1].index, 10);
+    }
+}
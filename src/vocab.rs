@@ -1,30 +1,99 @@
 use bitvec::{order::Msb0, view::BitView};
 use rand::{
     distributions::{weighted::WeightedIndex, Distribution},
+    rngs::StdRng,
     seq::SliceRandom,
-    thread_rng, Rng,
+    thread_rng, Rng, SeedableRng,
 };
 use serde::{Deserialize, Serialize};
-use smallvec::ToSmallVec;
-use std::fmt;
+use std::{fmt, sync::OnceLock};
 
 use crate::*;
 
-enum ClusterInitMethod {
-    #[allow(dead_code)]
+#[cfg(feature = "bincode")]
+const MAGIC: &[u8; 8] = b"ABOWVOC\0";
+
+/// Version of the vocabulary file format written by [`Vocabulary::save`]. Files of other
+/// versions are refused with [`BowErr::UnsupportedVersion`], except files written before the
+/// format was versioned (abow 0.4.2 and earlier), which are converted when loaded.
+pub const VOCABULARY_VERSION: u32 = 1;
+
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone, Copy, Default)]
+/// How the initial cluster centers of each k-means step are chosen.
+pub enum ClusterInitMethod {
+    /// k training features at distinct positions, chosen uniformly at random. Features which
+    /// occur several times in the training set can still be chosen more than once.
     Random,
+    /// k-means++: each next center is chosen with probability proportional to its
+    /// distance from the centers chosen so far.
+    #[default]
     #[allow(clippy::upper_case_acronyms)]
     KMeansPP,
 }
 
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone, Copy)]
+/// Parameters for building a vocabulary. See [`Vocabulary::create_with_params`].
+pub struct TrainingParams {
+    /// Branching factor (Must be >= 2).
+    pub k: usize,
+    /// Max number of levels (Must be >= 1).
+    pub l: usize,
+    /// Initialization of each k-means step.
+    pub init: ClusterInitMethod,
+    /// Clusters with fewer training features than this become words instead of being split
+    /// further (Must be >= 1). The default of 2 splits every cluster of more than one feature.
+    pub min_cluster_size: usize,
+    /// Seed of the random number generator, for reproducible vocabularies.
+    pub seed: Option<u64>,
+}
+
+impl Default for TrainingParams {
+    fn default() -> Self {
+        Self {
+            k: 10,
+            l: 4,
+            init: ClusterInitMethod::KMeansPP,
+            min_cluster_size: 2,
+            seed: None,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone, Copy)]
+/// Parameters for soft assignment of descriptors to words. See [`Vocabulary::transform_soft`].
+pub struct SoftAssignment {
+    /// Number of closest children explored at each level of the tree (Must be >= 1).
+    pub branches: usize,
+    /// Number of nearest words each descriptor contributes to (Must be >= 1).
+    pub words: usize,
+    /// Width of the Gaussian kernel on Hamming distance, in bits (Must be > 0).
+    pub sigma: f32,
+}
+
+impl Default for SoftAssignment {
+    fn default() -> Self {
+        Self {
+            branches: 2,
+            words: 3,
+            sigma: 8.,
+        }
+    }
+}
+
 #[derive(Serialize, Deserialize, PartialEq, Clone, Default)]
 /// Visual vocabulary built from a collection of image features.
 pub struct Vocabulary {
-    blocks: Vec<Block>,
-    k: usize,
-    levels: usize,
-    num_blocks: usize,
-    num_leaves: usize,
+    pub(crate) blocks: Vec<Block>,
+    pub(crate) k: usize,
+    pub(crate) levels: usize,
+    pub(crate) num_blocks: usize,
+    pub(crate) num_leaves: usize,
+    pub(crate) nodes_per_level: Vec<usize>,
+    pub(crate) burstiness: Burstiness,
+    #[serde(skip)]
+    pub(crate) tree_index: tree::TreeIndexCache,
+    #[serde(skip)]
+    pub(crate) fingerprint: FingerprintCache,
 }
 
 /// Vocabulary API
@@ -33,7 +102,22 @@ impl Vocabulary {
     /// representation with respect to the Vocabulary. Descriptor is l1 normalized.
     /// Returns Err if features is empty.
     pub fn transform(&self, features: &[Desc]) -> BowResult<BoW> {
-        self.transform_inner(features, false).map(|res| res.0)
+        self.transform_inner(features, false, &self.burstiness)
+            .map(|res| res.0)
+    }
+
+    /// Transform a vector of binary descriptors into its bag of words representation,
+    /// using the given burst suppression instead of the Vocabulary's. Descriptor is l1 normalized.
+    /// Its fingerprint is that of the Vocabulary with this burst suppression.
+    /// Returns Err if features is empty or the burstiness parameters are invalid.
+    pub fn transform_with_burstiness(
+        &self,
+        features: &[Desc],
+        burstiness: &Burstiness,
+    ) -> BowResult<BoW> {
+        burstiness.validate(self.num_leaves)?;
+        self.transform_inner(features, false, burstiness)
+            .map(|res| res.0)
     }
 
     /// Transform a vector of binary descriptors into its bag of words
@@ -46,43 +130,385 @@ impl Vocabulary {
     /// `di.len() <= l` (number of levels), and `di[j]` is the id of the node matching `feature[i]`
     /// at level `j` in the Vocabulary tree.
     pub fn transform_with_direct_idx(&self, features: &[Desc]) -> BowResult<(BoW, DirectIdx)> {
-        self.transform_inner(features, true)
+        self.transform_inner(features, true, &self.burstiness)
+    }
+
+    /// Transform a vector of binary descriptors into its bag of words representation, like
+    /// [`Self::transform_with_direct_idx`], and pair each feature's path with its keypoint.
+    /// Returns Err if features is empty, or if there is not one keypoint per feature.
+    pub fn transform_with_keypoints(
+        &self,
+        keypoints: &[KeyPoint],
+        features: &[Desc],
+    ) -> BowResult<(BoW, KeyPointIdx)> {
+        if keypoints.len() != features.len() {
+            return Err(BowErr::InvalidParams("expected one keypoint per feature"));
+        }
+        let (bow, di) = self.transform_with_direct_idx(features)?;
+        Ok((bow, keypoints.iter().cloned().zip(di).collect()))
+    }
+
+    /// Transform a vector of binary descriptors into a bag of words over the nodes
+    /// at depth `level` of the Vocabulary tree, for coarse matching. Descriptor is l1 normalized.
+    /// Returns Err if features is empty or `level` is not in `1..=l`.
+    ///
+    /// Each feature is assigned to the node it reaches at `level`, or to its word if the word
+    /// is shallower than `level`. The result is indexed by node id (see [`Self::level_nodes`]),
+    /// and has length `level_nodes(level).end`. Entries for nodes above `level` which are not
+    /// words are always zero. `transform_at_level(features, l)` ranks images like `transform`.
+    pub fn transform_at_level(&self, features: &[Desc], level: usize) -> BowResult<BoW> {
+        if features.is_empty() {
+            return Err(BowErr::NoFeatures);
+        }
+        if level < 1 || level > self.levels || self.blocks.is_empty() {
+            return Err(BowErr::InvalidParams(
+                "level must be between 1 and the number of levels",
+            ));
+        }
+
+        // Level vectors are indexed by node, so they are not comparable to word vectors
+        let mut fingerprint = Fnv1a(self.fingerprint());
+        fingerprint.write(&(level as u64).to_le_bytes());
+        let mut bow = BoW(vec![0.; self.level_nodes(level).end], Some(fingerprint.0));
+        for feature in features {
+            let mut block = &self.blocks[0];
+            let mut depth = 1;
+            loop {
+                let best_child = best_child(&block.children, feature);
+                match block.children.ids[best_child] {
+                    NodeId::Block(id) if depth < level => {
+                        block = &self.blocks[id];
+                        depth += 1;
+                    }
+                    _ => {
+                        let node_id = block.children.nodes[best_child];
+                        bow.0[node_id] += block.children.weights[best_child];
+                        break;
+                    }
+                }
+            }
+        }
+        self.burstiness.apply_to_nodes(&mut bow);
+        bow.normalize();
+
+        Ok(bow)
+    }
+
+    /// Transform a vector of binary descriptors into its bag of words representation,
+    /// assigning each descriptor to several nearby words instead of only the nearest one.
+    /// Descriptor is l1 normalized. Returns Err if features is empty or the parameters are invalid.
+    ///
+    /// The tree is searched by following the `params.branches` closest children at each level.
+    /// Of the words reached, each descriptor contributes to the `params.words` closest,
+    /// in proportion to `exp(-d^2 / (2 * sigma^2))` where `d` is the Hamming distance to the word.
+    /// This reduces quantization error for descriptors near cluster boundaries.
+    /// With one branch and one word this is equivalent to [`Self::transform`].
+    pub fn transform_soft(&self, features: &[Desc], params: &SoftAssignment) -> BowResult<BoW> {
+        if features.is_empty() {
+            return Err(BowErr::NoFeatures);
+        }
+        if params.branches < 1 || params.words < 1 || params.sigma.is_nan() || params.sigma <= 0. {
+            return Err(BowErr::InvalidParams(
+                "soft assignment needs branches >= 1, words >= 1 and sigma > 0",
+            ));
+        }
+
+        let inv_2sigma2 = 1. / (2. * params.sigma * params.sigma);
+        let mut bow = BoW(vec![0.; self.num_leaves], Some(self.fingerprint()));
+        let mut stack: Vec<usize> = Vec::new();
+        let mut candidates: Vec<(u8, usize)> = Vec::new();
+        // (word id, distance, weight)
+        let mut words: Vec<(usize, u8, f32)> = Vec::new();
+        for feature in features {
+            words.clear();
+            stack.push(0);
+            while let Some(b) = stack.pop() {
+                let children = &self.blocks[b].children;
+
+                // explore the closest children of this block
+                candidates.clear();
+                candidates.extend(
+                    children
+                        .features
+                        .iter()
+                        .map(|c| hamming(feature, c))
+                        .zip(0..),
+                );
+                candidates.sort_unstable();
+                for &(d, child) in candidates.iter().take(params.branches) {
+                    match children.ids[child] {
+                        NodeId::Block(id) => stack.push(id),
+                        NodeId::Leaf(word_id) => words.push((word_id, d, children.weights[child])),
+                    }
+                }
+            }
+
+            // weight the nearest words by a kernel on their distance. Distances are
+            // offset by the nearest one, which cancels out after normalization.
+            words.sort_unstable_by_key(|w| w.1);
+            words.truncate(params.words);
+            let d_min = words[0].1 as f32;
+            let kernel = |d: u8| (-((d as f32).powi(2) - d_min.powi(2)) * inv_2sigma2).exp();
+            let total: f32 = words.iter().map(|w| kernel(w.1)).sum();
+            for &(word_id, d, weight) in words.iter() {
+                bow.0[word_id] += weight * kernel(d) / total;
+            }
+        }
+        self.burstiness.apply(&mut bow)?;
+        bow.normalize();
+
+        Ok(bow)
+    }
+
+    /// Transform a vector of binary descriptors into its bag of words representation,
+    /// and compute the residual signature of each descriptor relative to its word.
+    /// Returns Err if features is empty.
+    ///
+    /// The signatures allow matches between descriptors which fall into the same word,
+    /// but lie far apart inside its cluster, to be rejected. See [`EmbeddedBoW::score`].
+    pub fn transform_with_signatures(&self, features: &[Desc]) -> BowResult<EmbeddedBoW> {
+        if features.is_empty() {
+            return Err(BowErr::NoFeatures);
+        }
+
+        // Each feature descends the tree once, for both its word and its signature
+        let mut bow = BoW(vec![0.; self.num_leaves], Some(self.fingerprint()));
+        let mut signatures: Vec<WordSignature> = Vec::with_capacity(features.len());
+        for feature in features {
+            let (children, leaf) = self.leaf_of(feature);
+            let word = children.ids[leaf].get_wid();
+            let weight = children.weights[leaf];
+            bow.0[word] += weight;
+            signatures.push(WordSignature {
+                word: word as u32,
+                signature: embedding::residual_signature(feature, &children.features[leaf]),
+                weight,
+            });
+        }
+        signatures.sort_unstable_by_key(|s| s.word);
+        self.burstiness.apply(&mut bow)?;
+        bow.normalize();
+
+        Ok(EmbeddedBoW { bow, signatures })
+    }
+
+    /// Burst suppression applied by all transforms of this Vocabulary.
+    pub fn burstiness(&self) -> &Burstiness {
+        &self.burstiness
+    }
+
+    /// Set the burst suppression applied by all transforms of this Vocabulary.
+    /// Returns Err if the parameters are invalid.
+    pub fn set_burstiness(&mut self, burstiness: Burstiness) -> BowResult<()> {
+        burstiness.validate(self.num_leaves)?;
+        self.burstiness = burstiness;
+        Ok(())
+    }
+
+    /// Hash identifying how this vocabulary transforms features: the tree, its word weights and
+    /// its burst suppression. Vocabularies with equal fingerprints produce the same BoW vectors,
+    /// so BoW vectors of vocabularies with different fingerprints should not be compared.
+    /// The hash is the same on every platform.
+    pub fn fingerprint(&self) -> u64 {
+        self.fingerprint_with(&self.burstiness)
+    }
+
+    /// Fingerprint of the vocabulary with another burst suppression.
+    fn fingerprint_with(&self, burstiness: &Burstiness) -> u64 {
+        let tree = *self.fingerprint.0.get_or_init(|| {
+            let mut hash = Fnv1a::default();
+            for v in [self.k, self.levels, self.num_leaves, self.blocks.len()] {
+                hash.write(&(v as u64).to_le_bytes());
+            }
+            for block in self.blocks.iter() {
+                let children = &block.children;
+                for ((feature, weight), id) in children
+                    .features
+                    .iter()
+                    .zip(&children.weights)
+                    .zip(&children.ids)
+                {
+                    hash.write(feature);
+                    hash.write(&weight.to_bits().to_le_bytes());
+                    let id = match id {
+                        NodeId::Block(b) => (*b as u64) << 1,
+                        NodeId::Leaf(w) => (*w as u64) << 1 | 1,
+                    };
+                    hash.write(&id.to_le_bytes());
+                }
+            }
+            hash.0
+        });
+        let mut hash = Fnv1a(tree);
+        for v in [burstiness.cap, burstiness.power] {
+            hash.write(&v.map_or(u64::MAX, |v| v.to_bits() as u64).to_le_bytes());
+        }
+        for idf in burstiness.idf.iter().flatten() {
+            hash.write(&idf.to_bits().to_le_bytes());
+        }
+        hash.0
+    }
+
+    /// Number of words (leaves) in the vocabulary, which is the length of each BoW vector.
+    pub fn num_words(&self) -> usize {
+        self.num_leaves
+    }
+
+    /// Number of non-leaf nodes in the vocabulary, excluding the root.
+    pub fn num_blocks(&self) -> usize {
+        self.num_blocks
+    }
+
+    /// Branching factor `k` the vocabulary was built with.
+    pub fn branching_factor(&self) -> usize {
+        self.k
+    }
+
+    /// Maximum number of levels `l` the vocabulary was built with.
+    pub fn levels(&self) -> usize {
+        self.levels
+    }
+
+    /// Number of nodes at each depth of the tree. Index 0 is the root, so
+    /// `num_nodes_per_level()[1]` is the number of children of the root.
+    pub fn num_nodes_per_level(&self) -> &[usize] {
+        &self.nodes_per_level
+    }
+
+    /// Range of the stable node ids of the nodes at depth `level`.
+    ///
+    /// Nodes are numbered breadth first, starting with 0 for the root,
+    /// so the ids of all nodes at depth <= `level` are `0..level_nodes(level).end`.
+    pub fn level_nodes(&self, level: usize) -> std::ops::Range<usize> {
+        let level = level.min(self.nodes_per_level.len().saturating_sub(1));
+        let start: usize = self.nodes_per_level[..level].iter().sum();
+        start..start + self.nodes_per_level.get(level).copied().unwrap_or(0)
     }
 
     /// Build a vocabulary from a collection of descriptors.
     ///
     /// Args:
-    /// - k: Branching factor
-    /// - l: Max number of levels (Should be <= 5)
-    pub fn create(features: &[Desc], k: usize, l: usize) -> Self {
+    /// - k: Branching factor (Must be >= 2)
+    /// - l: Max number of levels (Must be >= 1)
+    ///
+    /// Returns Err if features is empty or the parameters are out of range.
+    pub fn create(features: &[Desc], k: usize, l: usize) -> BowResult<Self> {
+        let params = TrainingParams {
+            k,
+            l,
+            ..Default::default()
+        };
+        Self::create_with_params(features, &params)
+    }
+
+    /// Build a vocabulary from a collection of descriptors, like [`Self::create`],
+    /// but with a seeded random number generator so that the result is reproducible.
+    pub fn create_seeded(features: &[Desc], k: usize, l: usize, seed: u64) -> BowResult<Self> {
+        let params = TrainingParams {
+            k,
+            l,
+            seed: Some(seed),
+            ..Default::default()
+        };
+        Self::create_with_params(features, &params)
+    }
+
+    /// Build a vocabulary from a collection of descriptors with full control over training.
+    /// Returns Err if features is empty or the parameters are out of range.
+    pub fn create_with_params(features: &[Desc], params: &TrainingParams) -> BowResult<Self> {
+        let mut rng = match params.seed {
+            Some(seed) => StdRng::seed_from_u64(seed),
+            None => StdRng::from_rng(thread_rng()).expect("rng init err"),
+        };
+        Self::create_inner(features, params, &mut rng)
+    }
+
+    /// Weight each word by its inverse document frequency `ln(N / n_w)` in a set of `N`
+    /// training images, `n_w` of which contain word `w`. Rare words then count for more
+    /// than common ones. The weights of a new vocabulary are all 1, and the new weights do not
+    /// depend on the previous ones. Returns Err if any image has no features.
+    pub fn set_idf_weights<D: AsRef<[Desc]>>(&mut self, images: &[D]) -> BowResult<()> {
+        // Count the images each word is assigned in, regardless of its current weight
+        let mut doc_freq = vec![0_usize; self.num_leaves];
+        let mut last_image = vec![usize::MAX; self.num_leaves];
+        for (i, image) in images.iter().enumerate() {
+            if image.as_ref().is_empty() {
+                return Err(BowErr::NoFeatures);
+            }
+            for feature in image.as_ref() {
+                let (children, leaf) = self.leaf_of(feature);
+                let word = children.ids[leaf].get_wid();
+                if last_image[word] != i {
+                    last_image[word] = i;
+                    doc_freq[word] += 1;
+                }
+            }
+        }
+        let idf = burstiness::idf_from_counts(&doc_freq, images.len());
+        for block in self.blocks.iter_mut() {
+            let children = &mut block.children;
+            for (weight, id) in children.weights.iter_mut().zip(&children.ids) {
+                if let NodeId::Leaf(word_id) = id {
+                    *weight = idf[*word_id];
+                }
+            }
+        }
+        self.fingerprint = Default::default();
+        Ok(())
+    }
+
+    fn create_inner(
+        features: &[Desc],
+        params: &TrainingParams,
+        rng: &mut StdRng,
+    ) -> BowResult<Self> {
+        if params.k < 2 {
+            return Err(BowErr::InvalidParams("branching factor k must be >= 2"));
+        }
+        if params.l < 1 {
+            return Err(BowErr::InvalidParams("number of levels l must be >= 1"));
+        }
+        if params.min_cluster_size < 1 {
+            return Err(BowErr::InvalidParams("min_cluster_size must be >= 1"));
+        }
+        if features.is_empty() {
+            return Err(BowErr::NoFeatures);
+        }
+
         // Start with root of tree
-        let mut v = Self::empty(k, l);
+        let mut v = Self::empty(params.k, params.l);
 
         // Build with recursive k-means clustering of features
-        v.cluster(features, vec![0], 1);
+        v.cluster(features, 0, 1, params, rng);
 
         // Sort by block id
-        v.blocks.sort_by(|a, b| a.id.get_bid().cmp(&b.id.get_bid()));
+        v.blocks.sort_by_key(|b| b.id.get_bid());
 
-        v
+        // Assign stable node ids, level by level
+        v.index_nodes();
+
+        Ok(v)
     }
 
-    /// Load an ABoW vocabulary from a file
+    /// Load an ABoW vocabulary from a file. Unversioned files written by abow 0.4.2 and earlier
+    /// are converted to the current layout, which [`Self::save`] writes back.
+    /// Returns Err if the file was saved in another format version, see [`VOCABULARY_VERSION`].
     #[cfg(feature = "bincode")]
     pub fn load<P: AsRef<std::path::Path>>(file: P) -> BowResult<Self> {
-        let mut file = std::fs::File::open(file)?;
-        let mut buffer: Vec<u8> = Vec::new();
-        std::io::Read::read_to_end(&mut file, &mut buffer)?;
-        Ok(bincode::deserialize(&buffer)?)
+        let buffer = std::fs::read(file)?;
+        match read_versioned(&buffer, MAGIC, VOCABULARY_VERSION, "Vocabulary") {
+            Ok(data) => Ok(bincode::deserialize(data)?),
+            Err(e @ BowErr::UnsupportedVersion { version: 0, .. }) => {
+                LegacyVocabulary::read(&buffer).ok_or(e)
+            }
+            Err(e) => Err(e),
+        }
     }
 
     /// Save vocabulary to a file
     #[cfg(feature = "bincode")]
     pub fn save<P: AsRef<std::path::Path>>(&self, file: P) -> BowResult<()> {
-        let serialized = bincode::serialize(&self)?;
-        let mut file = std::fs::File::create(file)?;
-        std::io::Write::write_all(&mut file, &serialized)?;
-        Ok(())
+        save_versioned(file, MAGIC, VOCABULARY_VERSION, self)
     }
 }
 
@@ -91,62 +517,72 @@ impl Vocabulary {
 
 #[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
 /// A unit representing a non-leaf node in the vocabulary
-struct Block {
-    id: NodeId,
-    children: Children,
+pub(crate) struct Block {
+    pub(crate) id: NodeId,
+    pub(crate) children: Children,
 }
 
 #[derive(Serialize, Deserialize, PartialEq, Clone)]
 /// Data structure representing the child nodes of a block, which may
 /// or may not be leaves
-struct Children {
-    features: Vec<Desc>,
-    weights: Vec<f32>,
-    cluster_size: Vec<usize>,
-    ids: Vec<NodeId>,
+pub(crate) struct Children {
+    pub(crate) features: Vec<Desc>,
+    pub(crate) weights: Vec<f32>,
+    pub(crate) cluster_size: Vec<usize>,
+    pub(crate) ids: Vec<NodeId>,
+    /// Stable node id of each child, unique across the whole tree
+    pub(crate) nodes: Vec<usize>,
 }
 
 #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
-/// Unique identifier for a node. The Leaf variant stores ids of all its parents,
-/// which is equivalent to the DirectIndex for any feature matching that leaf.
-enum NodeId {
+/// Unique identifier for a node. Block ids index into `Vocabulary::blocks` and
+/// leaf ids are word ids. Paths through the tree are recorded during traversal
+/// rather than stored, so the tree has no depth-dependent storage.
+pub(crate) enum NodeId {
     Block(usize),
-    Leaf(IdPath),
+    Leaf(usize),
 }
 
 impl Vocabulary {
-    fn transform_inner(&self, features: &[Desc], di: bool) -> BowResult<(BoW, DirectIdx)> {
+    fn transform_inner(
+        &self,
+        features: &[Desc],
+        di: bool,
+        burstiness: &Burstiness,
+    ) -> BowResult<(BoW, DirectIdx)> {
         if features.is_empty() {
             return Err(BowErr::NoFeatures);
         }
 
-        let mut bow = BoW(vec![0.; self.num_leaves]);
+        let mut bow = BoW(
+            vec![0.; self.num_leaves],
+            Some(self.fingerprint_with(burstiness)),
+        );
         let mut direct_idx: DirectIdx = Vec::with_capacity(features.len());
         for feature in features {
+            let mut path = IdPath::new();
+
             // start at root block
             let mut block = &self.blocks[0];
 
             // traverse tree
             loop {
-                let mut best_child: (u8, usize) = (u8::MAX, 0);
-                for (child, child_feat) in block.children.features.iter().enumerate() {
-                    let d = hamming(feature, child_feat);
-                    if d < best_child.0 {
-                        best_child = (d, child)
-                    }
-                }
-                match &block.children.ids[best_child.1] {
+                let best_child = best_child(&block.children, feature);
+                match block.children.ids[best_child] {
                     NodeId::Block(id) => {
-                        block = &self.blocks[*id];
+                        if di {
+                            path.push(id as u32);
+                        }
+                        block = &self.blocks[id];
                     }
-                    NodeId::Leaf(ids) => {
+                    NodeId::Leaf(word_id) => {
                         if di {
                             // add word parent ids to direct index
-                            direct_idx.push(ids.clone());
+                            path.push(word_id as u32);
+                            direct_idx.push(path);
                         }
                         // add word/leaf id and weight to result
-                        let word_id = *ids.last().unwrap();
-                        let weight = block.children.weights[best_child.1];
+                        let weight = block.children.weights[best_child];
                         match bow.0.get_mut(word_id) {
                             Some(w) => *w += weight,
                             None => bow.0[word_id] = weight,
@@ -156,27 +592,41 @@ impl Vocabulary {
                 }
             }
         }
-        // Normalize BoW vector
-        let sum: f32 = bow.0.iter().sum();
-        if sum > 0. {
-            let inv_sum = 1. / sum;
-            for w in bow.0.iter_mut() {
-                *w *= inv_sum;
-            }
-        }
+        burstiness.apply(&mut bow)?;
+        bow.normalize();
 
         Ok((bow, direct_idx))
     }
 
-    fn cluster(&mut self, features: &[Desc], parent_ids: Vec<usize>, curr_level: usize) {
+    /// Descend the tree to the word closest to a feature. Returns the children of
+    /// the word's parent block, and the index of the word among them.
+    pub(crate) fn leaf_of(&self, feature: &Desc) -> (&Children, usize) {
+        let mut block = &self.blocks[0];
+        loop {
+            let best_child = best_child(&block.children, feature);
+            match block.children.ids[best_child] {
+                NodeId::Block(id) => block = &self.blocks[id],
+                NodeId::Leaf(_) => return (&block.children, best_child),
+            }
+        }
+    }
+
+    fn cluster(
+        &mut self,
+        features: &[Desc],
+        block_id: usize,
+        curr_level: usize,
+        params: &TrainingParams,
+        rng: &mut StdRng,
+    ) {
         // println!(
-        //     "KMeans step with {} features. parents: {:?}, level {}",
+        //     "KMeans step with {} features. block: {}, level {}",
         //     features.len(),
-        //     parent_ids,
+        //     block_id,
         //     curr_level
         // );
 
-        let mut clusters = self.initialize_clusters(features, ClusterInitMethod::KMeansPP);
+        let mut clusters = self.initialize_clusters(features, params.init, rng);
         let mut groups = vec![Vec::new(); clusters.len()];
 
         loop {
@@ -213,18 +663,20 @@ impl Vocabulary {
         assert_eq!(groups.len(), clusters.len());
 
         // create block
+        let min_size = params.min_cluster_size.max(2);
         let ids: Vec<_> = groups
             .iter()
-            .map(|g| self.next_node_id(curr_level == self.levels || g.len() == 1, &parent_ids))
+            .map(|g| self.next_node_id(curr_level == self.levels || g.len() < min_size))
             .collect();
         let children = Children {
             weights: vec![1.; groups.len()],
             ids: ids.clone(),
             cluster_size: groups.iter().map(|g| g.len()).collect(),
             features: clusters,
+            nodes: Vec::new(),
         };
         let block = Block {
-            id: NodeId::Block(*parent_ids.last().unwrap()),
+            id: NodeId::Block(block_id),
             children,
         };
         self.blocks.push(block);
@@ -239,18 +691,19 @@ impl Vocabulary {
                 // get features from child cluster
                 let features: Vec<Desc> = groups[i].iter().map(|&j| features[j]).collect();
 
-                // update parent ids
-                let mut ids = parent_ids.clone();
-                ids.push(id.get_bid());
-
                 // perform clustering on child features
-                self.cluster(&features, ids, curr_level + 1);
+                self.cluster(&features, id.get_bid(), curr_level + 1, params, rng);
             }
         }
     }
 
     /// Initialize clusters for kmeans
-    fn initialize_clusters(&self, features: &[Desc], method: ClusterInitMethod) -> Vec<Desc> {
+    fn initialize_clusters(
+        &self,
+        features: &[Desc],
+        method: ClusterInitMethod,
+        rng: &mut StdRng,
+    ) -> Vec<Desc> {
         // if fewer than k unique features, simply return them
         if features.len() <= self.k {
             return features.to_vec();
@@ -265,21 +718,16 @@ impl Vocabulary {
         }
 
         match method {
-            ClusterInitMethod::Random => self.init_random(features),
-            ClusterInitMethod::KMeansPP => self.init_kmeanspp(features),
+            ClusterInitMethod::Random => self.init_random(features, rng),
+            ClusterInitMethod::KMeansPP => self.init_kmeanspp(features, rng),
         }
     }
 
-    fn init_random(&self, features: &[Desc]) -> Vec<Desc> {
-        let mut rng = thread_rng();
-        features
-            .choose_multiple(&mut rng, self.k)
-            .cloned()
-            .collect()
+    fn init_random(&self, features: &[Desc], rng: &mut StdRng) -> Vec<Desc> {
+        features.choose_multiple(rng, self.k).cloned().collect()
     }
 
-    fn init_kmeanspp(&self, features: &[Desc]) -> Vec<Desc> {
-        let mut rng = thread_rng();
+    fn init_kmeanspp(&self, features: &[Desc], rng: &mut StdRng) -> Vec<Desc> {
         let mut features = features.to_owned();
         let mut centroids = Vec::with_capacity(self.k);
         // 1. Randomly select the first centroid.
@@ -288,7 +736,7 @@ impl Vocabulary {
 
         while centroids.len() < self.k {
             // 2. For each data point compute its distance from the nearest, previously chosen centroid.
-            let mut dists: Vec<f32> = vec![std::u8::MAX as f32; features.len()];
+            let mut dists: Vec<f32> = vec![u8::MAX as f32; features.len()];
             for (i, f) in features.iter().enumerate() {
                 for c in centroids.iter() {
                     dists[i] = f32::min(hamming(f, c) as f32, dists[i]);
@@ -297,7 +745,7 @@ impl Vocabulary {
             // 3. Select the next centroid from the data points such that the probability of choosing a point
             // as centroid is directly proportional to its distance from the nearest, previously chosen centroid.
             let centroid_weights = WeightedIndex::new(dists).expect("weighted index err");
-            let weighted_random_idx = centroid_weights.sample(&mut rng);
+            let weighted_random_idx = centroid_weights.sample(rng);
             centroids.push(features.remove(weighted_random_idx));
         }
 
@@ -327,18 +775,38 @@ impl Vocabulary {
     }
 
     /// Provide the next NodeId, either leaf/word or block.
-    fn next_node_id(&mut self, leaf: bool, parent_ids: &[usize]) -> NodeId {
+    fn next_node_id(&mut self, leaf: bool) -> NodeId {
         if leaf {
-            // Leaf node will hold the block ids of its parents in addition to leaf id, to facilitate getting direct index later
-            let mut new_parent_ids = parent_ids[1..].to_smallvec(); //  drop the first parent which is always 0
-            new_parent_ids.push(self.num_leaves); // Add leaf id
             self.num_leaves += 1;
-            NodeId::Leaf(new_parent_ids)
+            NodeId::Leaf(self.num_leaves - 1)
         } else {
             self.num_blocks += 1;
             NodeId::Block(self.num_blocks)
         }
     }
+    /// Assign each node a stable id, numbering the tree breadth first so that
+    /// the nodes at each level have consecutive ids.
+    fn index_nodes(&mut self) {
+        self.nodes_per_level = vec![1]; // root
+        let mut next_id = 1;
+        let mut frontier = vec![0];
+        while !frontier.is_empty() {
+            let mut next_frontier = Vec::new();
+            for &b in frontier.iter() {
+                let children = &mut self.blocks[b].children;
+                children.nodes = (next_id..next_id + children.ids.len()).collect();
+                next_id += children.ids.len();
+                next_frontier.extend(children.ids.iter().filter_map(|id| match id {
+                    NodeId::Block(b) => Some(*b),
+                    NodeId::Leaf(_) => None,
+                }));
+            }
+            let level_start: usize = self.nodes_per_level.iter().sum();
+            self.nodes_per_level.push(next_id - level_start);
+            frontier = next_frontier;
+        }
+    }
+
     fn empty(k: usize, l: usize) -> Self {
         Self {
             blocks: Vec::new(),
@@ -346,8 +814,155 @@ impl Vocabulary {
             num_blocks: 0,
             num_leaves: 0,
             levels: l,
+            nodes_per_level: Vec::new(),
+            burstiness: Burstiness::default(),
+            tree_index: Default::default(),
+            fingerprint: Default::default(),
+        }
+    }
+}
+
+#[cfg(feature = "bincode")]
+#[derive(Deserialize)]
+/// Layout of the unversioned files written by abow 0.4.2 and earlier. Blocks are in the same
+/// order, but leaves hold the block ids on their path followed by their word id, and there
+/// are no stable node ids or burstiness parameters.
+struct LegacyVocabulary {
+    blocks: Vec<LegacyBlock>,
+    k: usize,
+    levels: usize,
+    num_blocks: usize,
+    num_leaves: usize,
+}
+
+#[cfg(feature = "bincode")]
+#[derive(Deserialize)]
+struct LegacyBlock {
+    id: LegacyNodeId,
+    children: LegacyChildren,
+}
+
+#[cfg(feature = "bincode")]
+#[derive(Deserialize)]
+struct LegacyChildren {
+    features: Vec<Desc>,
+    weights: Vec<f32>,
+    cluster_size: Vec<usize>,
+    ids: Vec<LegacyNodeId>,
+}
+
+#[cfg(feature = "bincode")]
+#[derive(Deserialize)]
+enum LegacyNodeId {
+    Block(usize),
+    Leaf(Vec<usize>),
+}
+
+#[cfg(feature = "bincode")]
+impl LegacyVocabulary {
+    /// Convert a file in the legacy layout, or None if it is not one. The whole buffer must be
+    /// read and describe a tree, so that files in other layouts are not mistaken for it.
+    fn read(buffer: &[u8]) -> Option<Vocabulary> {
+        use bincode::Options;
+        let legacy: Self = bincode::DefaultOptions::new()
+            .with_fixint_encoding()
+            .deserialize(buffer)
+            .ok()?;
+        // The root block is not counted
+        let num_blocks = legacy.blocks.len();
+        if num_blocks != legacy.num_blocks.checked_add(1)? {
+            return None;
+        }
+        let mut voc = Vocabulary::empty(legacy.k, legacy.levels);
+        voc.num_blocks = legacy.num_blocks;
+        voc.num_leaves = legacy.num_leaves;
+        let mut seen = vec![false; legacy.num_leaves];
+        for (b, block) in legacy.blocks.into_iter().enumerate() {
+            let c = block.children;
+            let n = c.ids.len();
+            let consistent = [c.features.len(), c.weights.len(), c.cluster_size.len()];
+            if !matches!(block.id, LegacyNodeId::Block(id) if id == b)
+                || n == 0
+                || consistent.iter().any(|&len| len != n)
+            {
+                return None;
+            }
+            let mut ids = Vec::with_capacity(n);
+            for id in c.ids {
+                ids.push(match id {
+                    // Blocks were numbered as they were created, after their parent
+                    LegacyNodeId::Block(child) if child > b && child < num_blocks => {
+                        NodeId::Block(child)
+                    }
+                    LegacyNodeId::Leaf(path) => {
+                        let word = *path.last()?;
+                        if std::mem::replace(seen.get_mut(word)?, true) {
+                            return None;
+                        }
+                        NodeId::Leaf(word)
+                    }
+                    LegacyNodeId::Block(_) => return None,
+                });
+            }
+            voc.blocks.push(Block {
+                id: NodeId::Block(b),
+                children: Children {
+                    features: c.features,
+                    weights: c.weights,
+                    cluster_size: c.cluster_size,
+                    ids,
+                    nodes: Vec::new(),
+                },
+            });
+        }
+        if seen.contains(&false) {
+            return None;
+        }
+        voc.index_nodes();
+        Some(voc)
+    }
+}
+
+#[derive(Clone, Default)]
+/// Fingerprint of a vocabulary's tree and weights, computed on first use and reset when they
+/// change.
+/// Not serialized, and ignored by comparisons since it is derived from the vocabulary.
+pub(crate) struct FingerprintCache(OnceLock<u64>);
+
+impl PartialEq for FingerprintCache {
+    fn eq(&self, _other: &Self) -> bool {
+        true
+    }
+}
+
+/// 64-bit FNV-1a hash, which unlike `DefaultHasher` is the same in every build.
+struct Fnv1a(u64);
+
+impl Default for Fnv1a {
+    fn default() -> Self {
+        Self(0xcbf2_9ce4_8422_2325)
+    }
+}
+
+impl Fnv1a {
+    fn write(&mut self, bytes: &[u8]) {
+        for &b in bytes {
+            self.0 = (self.0 ^ b as u64).wrapping_mul(0x0100_0000_01b3);
+        }
+    }
+}
+
+#[inline]
+/// Index of the child closest to a feature.
+fn best_child(children: &Children, feature: &Desc) -> usize {
+    let mut best_child: (u8, usize) = (u8::MAX, 0);
+    for (child, child_feat) in children.features.iter().enumerate() {
+        let d = hamming(feature, child_feat);
+        if d < best_child.0 {
+            best_child = (d, child)
         }
     }
+    best_child.1
 }
 
 #[inline]
@@ -365,6 +980,13 @@ impl NodeId {
             NodeId::Leaf(_) => unreachable!(),
         }
     }
+
+    fn get_wid(&self) -> usize {
+        match self {
+            NodeId::Leaf(i) => *i,
+            NodeId::Block(_) => unreachable!(),
+        }
+    }
 }
 
 impl fmt::Debug for Children {
@@ -379,35 +1001,222 @@ impl fmt::Debug for Children {
 
 impl fmt::Debug for Vocabulary {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        let mut clust_sizes: Vec<usize> = Vec::new();
-        for b in self.blocks.iter() {
-            for (i, &c) in b.children.cluster_size.iter().enumerate() {
-                if matches!(b.children.ids[i], NodeId::Leaf(_)) {
-                    clust_sizes.push(c);
-                }
-            }
-        }
-        let sum = clust_sizes.iter().sum::<usize>();
-        clust_sizes.sort_unstable();
+        let stats = self.stats();
         f.debug_struct("Vocabulary")
-            .field("Word/Leaf Nodes", &self.num_leaves)
-            .field("Other Nodes", &self.num_blocks)
-            .field("Levels", &self.levels)
-            .field("Branching Factor", &self.k)
-            .field("Total Training Features", &sum)
-            .field(
-                "Min Word Cluster Size",
-                &(clust_sizes.iter().min().unwrap()),
-            )
-            .field(
-                "Max Word Cluster Size",
-                &(clust_sizes.iter().max().unwrap()),
-            )
-            .field("Mean Word Cluster Size", &(sum / clust_sizes.len()))
-            .field(
-                "Median Word Cluster Size",
-                &clust_sizes[clust_sizes.len() / 2],
-            )
+            .field("Word/Leaf Nodes", &stats.num_words)
+            .field("Other Nodes", &stats.num_blocks)
+            .field("Levels", &stats.levels)
+            .field("Branching Factor", &stats.branching_factor)
+            .field("Total Training Features", &stats.training_features)
+            .field("Min Word Cluster Size", &stats.cluster_sizes.min)
+            .field("Max Word Cluster Size", &stats.cluster_sizes.max)
+            .field("Mean Word Cluster Size", &stats.cluster_sizes.mean)
+            .field("Median Word Cluster Size", &stats.cluster_sizes.median)
+            .field("Words Per Level", &stats.words_per_level)
+            .field("Imbalance Factor", &stats.imbalance)
             .finish()
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn create_validates_params() {
+        let features: Vec<Desc> = (0..100).map(|_| thread_rng().gen()).collect();
+        assert!(matches!(
+            Vocabulary::create(&features, 1, 3),
+            Err(BowErr::InvalidParams(_))
+        ));
+        assert!(matches!(
+            Vocabulary::create(&features, 4, 0),
+            Err(BowErr::InvalidParams(_))
+        ));
+        assert!(matches!(
+            Vocabulary::create(&[], 4, 3),
+            Err(BowErr::NoFeatures)
+        ));
+    }
+
+    #[test]
+    fn training_params() {
+        let features: Vec<Desc> = (0..1000).map(|_| thread_rng().gen()).collect();
+        let params = TrainingParams {
+            k: 4,
+            l: 4,
+            init: ClusterInitMethod::Random,
+            min_cluster_size: 50,
+            seed: Some(7),
+        };
+        let voc = Vocabulary::create_with_params(&features, &params).unwrap();
+        assert!(voc == Vocabulary::create_with_params(&features, &params).unwrap());
+        // Only clusters of at least 50 features are split
+        for node in voc.nodes().skip(1).filter(|n| !n.is_word()) {
+            assert!(node.cluster_size() >= 50);
+        }
+        let unlimited = Vocabulary::create_seeded(&features, 4, 4, 7).unwrap();
+        assert!(voc.num_words() < unlimited.num_words());
+
+        let invalid = TrainingParams {
+            min_cluster_size: 0,
+            ..params
+        };
+        assert!(Vocabulary::create_with_params(&features, &invalid).is_err());
+    }
+
+    #[test]
+    fn seeded_and_weighted() {
+        let features: Vec<Desc> = (0..1000).map(|_| thread_rng().gen()).collect();
+        let mut voc = Vocabulary::create_seeded(&features, 5, 3, 42).unwrap();
+        let same = Vocabulary::create_seeded(&features, 5, 3, 42).unwrap();
+        assert!(voc == same);
+        assert_eq!(voc.fingerprint(), same.fingerprint());
+
+        let images: Vec<&[Desc]> = features.chunks(100).collect();
+        let fingerprint = voc.fingerprint();
+        voc.set_idf_weights(&images).unwrap();
+        assert_ne!(voc.fingerprint(), fingerprint);
+        // Vectors of differently weighted vocabularies cannot be compared
+        let (bow, other) = (
+            voc.transform(&features[..50]).unwrap(),
+            same.transform(&features[..50]).unwrap(),
+        );
+        assert!(matches!(
+            bow.l1(&other),
+            Err(BowErr::VocabularyMismatch { .. })
+        ));
+        assert!(matches!(
+            bow.l1(&BoW(vec![0.; 3], None)),
+            Err(BowErr::DimensionMismatch { .. })
+        ));
+        assert!(bow.l1(&BoW(other.0, None)).is_ok());
+        let weights = voc.blocks.iter().flat_map(|b| b.children.weights.iter());
+        assert!(weights.clone().all(|&w| w >= 0.));
+        assert!(weights.clone().any(|&w| w != 1.));
+
+        // Words in every image get weight 0, and setting the weights again changes nothing
+        let weights = |v: &Vocabulary| -> Vec<f32> {
+            v.blocks
+                .iter()
+                .flat_map(|b| b.children.weights.iter().cloned())
+                .collect()
+        };
+        let images = [&features[..500], &features[..250]];
+        voc.set_idf_weights(&images).unwrap();
+        let first = weights(&voc);
+        assert!(first.contains(&0.));
+        voc.set_idf_weights(&images).unwrap();
+        assert_eq!(weights(&voc), first);
+    }
+
+    #[cfg(feature = "bincode")]
+    #[test]
+    fn file_version() {
+        let features: Vec<Desc> = (0..500).map(|_| thread_rng().gen()).collect();
+        let voc = Vocabulary::create(&features, 4, 3).unwrap();
+        let path = std::env::temp_dir().join(format!("abow_test_{}.voc", std::process::id()));
+        voc.save(&path).unwrap();
+        assert!(Vocabulary::load(&path).unwrap() == voc);
+
+        // Unversioned files which are not in the legacy layout are refused
+        std::fs::write(&path, bincode::serialize(&voc).unwrap()).unwrap();
+        assert!(matches!(
+            Vocabulary::load(&path),
+            Err(BowErr::UnsupportedVersion { version: 0, .. })
+        ));
+        std::fs::remove_file(&path).unwrap();
+
+        // vocabs/test.voc is vocabs/test-0.4.2.voc converted, and saved in the current format
+        let legacy = Vocabulary::load("vocabs/test-0.4.2.voc").unwrap();
+        let current = Vocabulary::load("vocabs/test.voc").unwrap();
+        assert!(legacy == current);
+        assert_eq!(legacy.fingerprint(), current.fingerprint());
+    }
+
+    #[test]
+    fn level_bow() {
+        let features: Vec<Desc> = (0..2000).map(|_| thread_rng().gen()).collect();
+        let voc = Vocabulary::create(&features, 5, 3).unwrap();
+        let levels = voc.num_nodes_per_level();
+        assert_eq!(levels.len(), 4);
+        assert_eq!(levels[0], 1);
+        assert_eq!(
+            levels.iter().sum::<usize>(),
+            voc.num_blocks + voc.num_leaves + 1
+        );
+
+        let coarse = voc.transform_at_level(&features[..100], 1).unwrap();
+        assert_eq!(coarse.0.len(), voc.level_nodes(1).end);
+        assert!((coarse.0.iter().sum::<f32>() - 1.).abs() < 1e-5);
+        assert!(voc.transform_at_level(&features, 4).is_err());
+
+        // At the deepest level, node ids map one-to-one onto words
+        let fine = voc.transform_at_level(&features[..100], 3).unwrap();
+        let bow = voc.transform(&features[..100]).unwrap();
+        let mut fine_weights: Vec<f32> = fine.0.into_iter().filter(|&w| w > 0.).collect();
+        let mut weights: Vec<f32> = bow.0.into_iter().filter(|&w| w > 0.).collect();
+        fine_weights.sort_by(|a, b| a.partial_cmp(b).unwrap());
+        weights.sort_by(|a, b| a.partial_cmp(b).unwrap());
+        assert_eq!(fine_weights, weights);
+    }
+
+    #[test]
+    fn soft_assignment() {
+        let features: Vec<Desc> = (0..2000).map(|_| thread_rng().gen()).collect();
+        let voc = Vocabulary::create(&features, 6, 3).unwrap();
+        let query = &features[..200];
+
+        let hard = SoftAssignment {
+            branches: 1,
+            words: 1,
+            ..Default::default()
+        };
+        assert_eq!(
+            voc.transform_soft(query, &hard).unwrap(),
+            voc.transform(query).unwrap()
+        );
+
+        let soft = voc
+            .transform_soft(query, &SoftAssignment::default())
+            .unwrap();
+        let nonzero = |bow: &BoW| bow.0.iter().filter(|&&w| w > 0.).count();
+        assert!(nonzero(&soft) >= nonzero(&voc.transform(query).unwrap()));
+        assert!((soft.0.iter().sum::<f32>() - 1.).abs() < 1e-5);
+    }
+
+    #[test]
+    fn keypoint_idx() {
+        let features: Vec<Desc> = (0..500).map(|_| thread_rng().gen()).collect();
+        let keypoints: Vec<KeyPoint> = (0..500)
+            .map(|i| KeyPoint {
+                x: i as f32,
+                ..Default::default()
+            })
+            .collect();
+        let voc = Vocabulary::create(&features, 4, 3).unwrap();
+
+        let (bow, kp_idx) = voc.transform_with_keypoints(&keypoints, &features).unwrap();
+        let (di_bow, di) = voc.transform_with_direct_idx(&features).unwrap();
+        assert_eq!(bow, di_bow);
+        for (i, (kp, path)) in kp_idx.iter().enumerate() {
+            assert_eq!(kp.x, i as f32);
+            assert_eq!(path, &di[i]);
+        }
+        assert!(voc
+            .transform_with_keypoints(&keypoints[1..], &features)
+            .is_err());
+    }
+
+    #[test]
+    fn deep_vocabulary() {
+        let features: Vec<Desc> = (0..3000).map(|_| thread_rng().gen()).collect();
+        let voc = Vocabulary::create(&features, 2, 10).unwrap();
+        let (bow, di) = voc.transform_with_direct_idx(&features[..50]).unwrap();
+        assert_eq!(di.len(), 50);
+        for path in di.iter() {
+            assert!(path.len() <= 10);
+            assert!(bow.0[*path.last().unwrap() as usize] > 0.);
+        }
+    }
+}
@@ -0,0 +1,273 @@
+//! Runs the `abow` binary on small inputs, one subcommand after the other as in the README.
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    process::Command,
+};
+
+use abow::{descriptor_file, Desc, ImageDescriptors};
+use rand::{rngs::StdRng, Rng, SeedableRng};
+use serde_json::Value;
+
+/// Empty directory for the files of one test.
+fn test_dir(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("abow_cli_{}_{}", name, std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+/// Run abow with `args`, and return its standard output. Panics if it fails.
+fn abow<S: AsRef<std::ffi::OsStr>>(args: &[S]) -> String {
+    let output = Command::new(env!("CARGO_BIN_EXE_abow"))
+        .args(args)
+        .output()
+        .unwrap();
+    assert!(
+        output.status.success(),
+        "abow failed: {}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+    String::from_utf8(output.stdout).unwrap()
+}
+
+/// Like [`abow`] with `--json`, parsing the output.
+fn abow_json<S: AsRef<std::ffi::OsStr>>(args: &[S]) -> Value {
+    let mut args: Vec<_> = args.iter().map(|a| a.as_ref().to_owned()).collect();
+    args.push("--json".into());
+    serde_json::from_str(&abow(&args)).unwrap()
+}
+
+/// Descriptor file of 12 images named 0.png to 11.png, where images 2i and 2i + 1 are noisy
+/// versions of the same place.
+fn descriptor_file(dir: &Path) -> PathBuf {
+    let mut rng = StdRng::seed_from_u64(5);
+    let places: Vec<Vec<Desc>> = (0..6)
+        .map(|_| (0..50).map(|_| rng.gen()).collect())
+        .collect();
+    let images: Vec<ImageDescriptors> = (0..12)
+        .map(|i| ImageDescriptors {
+            name: format!("{}.png", i),
+            descriptors: places[i / 2]
+                .iter()
+                .map(|d| {
+                    let mut d = *d;
+                    d[rng.gen_range(0..32)] ^= 1 << rng.gen_range(0..8);
+                    d
+                })
+                .collect(),
+            keypoints: None,
+        })
+        .collect();
+    let path = dir.join("images.abd");
+    descriptor_file::write_binary(&path, &images).unwrap();
+    path
+}
+
+#[test]
+fn train_transform_query() {
+    let dir = test_dir("query");
+    let images = descriptor_file(&dir);
+    let voc = dir.join("test.voc");
+    let bows = dir.join("bows");
+    abow(&[
+        "train".as_ref(),
+        images.as_os_str(),
+        "-o".as_ref(),
+        voc.as_os_str(),
+        "-k".as_ref(),
+        "8".as_ref(),
+        "-l".as_ref(),
+        "2".as_ref(),
+        "--seed".as_ref(),
+        "3".as_ref(),
+    ]);
+    let inspect = abow_json(&["inspect".as_ref(), voc.as_os_str()]);
+    assert!(inspect["words"].as_u64().unwrap() > 6);
+
+    let transform = abow_json(&[
+        "transform".as_ref(),
+        voc.as_os_str(),
+        images.as_os_str(),
+        "-o".as_ref(),
+        bows.as_os_str(),
+    ]);
+    assert_eq!(transform.as_array().unwrap().len(), 12);
+    assert!(bows.join("11.png.bow").is_file());
+
+    // The best matches of each image are itself and the other image of its place
+    let db = dir.join("bows.db");
+    let query = |database: &Path, save: bool| {
+        let mut args = vec![
+            "query".as_ref(),
+            voc.as_os_str(),
+            database.as_os_str(),
+            "-n".as_ref(),
+            "2".as_ref(),
+        ];
+        if save {
+            args.extend(["--save".as_ref(), db.as_os_str()]);
+        }
+        let queries: Vec<PathBuf> = (0..12)
+            .map(|i| bows.join(format!("{}.png.bow", i)))
+            .collect();
+        args.extend(queries.iter().map(|q| q.as_os_str()));
+        abow_json(&args)
+    };
+    let results = query(&bows, true);
+    for (i, result) in results.as_array().unwrap().iter().enumerate() {
+        let mut entries: Vec<&str> = result["matches"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|m| m["entry"].as_str().unwrap())
+            .collect();
+        entries.sort();
+        let place: Vec<PathBuf> = (i / 2 * 2..i / 2 * 2 + 2)
+            .map(|i| bows.join(format!("{}.png.bow", i)))
+            .collect();
+        assert_eq!(
+            entries,
+            [place[0].to_str().unwrap(), place[1].to_str().unwrap()]
+        );
+    }
+    // The saved database gives the same results
+    assert_eq!(query(&db, false), results);
+
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn export_and_convert() {
+    let dir = test_dir("convert");
+    let images = descriptor_file(&dir);
+    let voc = dir.join("test.voc");
+    abow(&[
+        "train".as_ref(),
+        images.as_os_str(),
+        "-o".as_ref(),
+        voc.as_os_str(),
+        "-k".as_ref(),
+        "4".as_ref(),
+        "-l".as_ref(),
+        "2".as_ref(),
+    ]);
+
+    let dot = dir.join("tree.dot");
+    abow(&["export".as_ref(), voc.as_os_str(), dot.as_os_str()]);
+    assert!(fs::read_to_string(&dot).unwrap().starts_with("digraph"));
+    let json = dir.join("tree.json");
+    abow(&[
+        "export".as_ref(),
+        voc.as_os_str(),
+        json.as_os_str(),
+        "--max-depth".as_ref(),
+        "1".as_ref(),
+    ]);
+    let tree: Value = serde_json::from_str(&fs::read_to_string(&json).unwrap()).unwrap();
+    assert!(tree.is_object());
+
+    // .voc -> .json -> .voc is lossless
+    let converted = dir.join("converted.json");
+    let back = dir.join("back.voc");
+    abow(&["convert".as_ref(), voc.as_os_str(), converted.as_os_str()]);
+    abow(&["convert".as_ref(), converted.as_os_str(), back.as_os_str()]);
+    assert_eq!(fs::read(&voc).unwrap(), fs::read(&back).unwrap());
+
+    // Vocabularies of abow 0.4.2 are upgraded
+    let legacy = Path::new(env!("CARGO_MANIFEST_DIR")).join("vocabs");
+    let upgraded = dir.join("upgraded.voc");
+    abow(&[
+        "convert".as_ref(),
+        legacy.join("test-0.4.2.voc").as_os_str(),
+        upgraded.as_os_str(),
+    ]);
+    assert_eq!(
+        fs::read(legacy.join("test.voc")).unwrap(),
+        fs::read(&upgraded).unwrap()
+    );
+
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn sweep() {
+    let dir = test_dir("sweep");
+    let images = descriptor_file(&dir);
+    let best = dir.join("best.voc");
+    let csv = dir.join("sweep.csv");
+    let result = abow_json(&[
+        "sweep".as_ref(),
+        images.as_os_str(),
+        images.as_os_str(),
+        "-o".as_ref(),
+        best.as_os_str(),
+        "-k".as_ref(),
+        "2,8".as_ref(),
+        "-l".as_ref(),
+        "2".as_ref(),
+        "--tolerance".as_ref(),
+        "1".as_ref(),
+        "--seed".as_ref(),
+        "3".as_ref(),
+        "--csv".as_ref(),
+        csv.as_os_str(),
+    ]);
+    assert_eq!(result["results"].as_array().unwrap().len(), 2);
+    assert_eq!(fs::read_to_string(&csv).unwrap().lines().count(), 3);
+    let inspect = abow_json(&["inspect".as_ref(), best.as_os_str()]);
+    let best = &result["results"][result["best"].as_u64().unwrap() as usize];
+    assert_eq!(inspect["words"], best["num_words"]);
+
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+/// The README workflow on a few images of data/test, which needs a feature extractor.
+#[cfg(any(feature = "opencv", feature = "native-features"))]
+#[test]
+fn images_of_data_test() {
+    let dir = test_dir("images");
+    let images = dir.join("images");
+    fs::create_dir_all(&images).unwrap();
+    let data = Path::new(env!("CARGO_MANIFEST_DIR")).join("data/test");
+    for name in ["100.jpg", "101.jpg", "150.jpg"] {
+        fs::copy(data.join(name), images.join(name)).unwrap();
+    }
+    let voc = dir.join("test.voc");
+    let bows = dir.join("bows");
+    abow(&[
+        "train".as_ref(),
+        images.as_os_str(),
+        "-o".as_ref(),
+        voc.as_os_str(),
+        "-k".as_ref(),
+        "6".as_ref(),
+        "-l".as_ref(),
+        "3".as_ref(),
+        "--seed".as_ref(),
+        "1".as_ref(),
+    ]);
+    abow(&[
+        "transform".as_ref(),
+        voc.as_os_str(),
+        images.as_os_str(),
+        "-o".as_ref(),
+        bows.as_os_str(),
+    ]);
+    let query = bows.join("100.jpg.bow");
+    let results = abow_json(&[
+        "query".as_ref(),
+        voc.as_os_str(),
+        bows.as_os_str(),
+        query.as_os_str(),
+    ]);
+    let matches = results[0]["matches"].as_array().unwrap();
+    assert_eq!(matches.len(), 3);
+    assert_eq!(matches[0]["entry"], query.to_str().unwrap());
+    assert_eq!(
+        matches[1]["entry"],
+        bows.join("101.jpg.bow").to_str().unwrap()
+    );
+
+    fs::remove_dir_all(&dir).unwrap();
+}